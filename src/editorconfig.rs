@@ -0,0 +1,245 @@
+use std::{collections::BTreeMap, fs::read_to_string, path::Path};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::fmt::Config;
+
+/// The `.editorconfig` properties that apply to the file at `path`, with their names
+/// and values in lowercase.
+///
+/// The `.editorconfig` files are read from the directory of `path` upwards until one
+/// has `root = true`, properties in closer files and later sections win.
+pub(crate) fn properties(path: &Path) -> BTreeMap<String, String> {
+    let Ok(path) = std::path::absolute(path) else { return BTreeMap::new() };
+    let mut files = vec![];
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        let file = current.join(".editorconfig");
+        if let Ok(source) = read_to_string(&file) {
+            let root = parse(&source).0;
+            files.push((current.to_path_buf(), source));
+            if root {
+                break;
+            }
+        }
+        dir = current.parent();
+    }
+
+    let mut properties = BTreeMap::new();
+    for (dir, source) in files.iter().rev() {
+        let Ok(relative) = path.strip_prefix(dir) else { continue };
+        for (glob, section) in parse(source).1 {
+            if !matches(&glob, relative) {
+                continue;
+            }
+            for (name, value) in section {
+                if value == "unset" {
+                    properties.remove(&name);
+                } else {
+                    properties.insert(name, value);
+                }
+            }
+        }
+    }
+    properties
+}
+
+/// Set the fields of `config` that the `.editorconfig` `properties` describe, except
+/// those the `explicit` keys of the tomlfmt.toml set.
+///
+/// `indent_style`, `indent_size`, `tab_width`, `end_of_line`, `insert_final_newline`
+/// and `max_line_length` are used. `trim_trailing_whitespace` is not supported,
+/// formatting always trims the whitespace at the end of lines.
+pub(crate) fn apply(
+    config: &mut Config,
+    properties: &BTreeMap<String, String>,
+    explicit: &[String],
+) {
+    let is_set = |key: &str| explicit.iter().any(|explicit| explicit == key);
+    let number = |name: &str| properties.get(name).and_then(|value| value.parse().ok());
+
+    if !is_set("indent_count") && !is_set("indent_string") {
+        let indent_size = match properties.get("indent_size").map(String::as_str) {
+            Some("tab") => number("tab_width"),
+            _ => number("indent_size"),
+        };
+        match properties.get("indent_style").map(String::as_str) {
+            Some("tab") => config.indent_string = Some("\t".to_owned()),
+            Some("space") | None => {
+                if let Some(indent_size) = indent_size {
+                    config.indent_count = indent_size;
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    if !is_set("crlf") {
+        match properties.get("end_of_line").map(String::as_str) {
+            Some("crlf") => config.crlf = Some(true),
+            Some("lf") => config.crlf = Some(false),
+            _ => {}
+        }
+    }
+    if !is_set("trailing_newline") {
+        match properties.get("insert_final_newline").map(String::as_str) {
+            Some("true") => config.trailing_newline = true,
+            Some("false") => config.trailing_newline = false,
+            _ => {}
+        }
+    }
+    if !is_set("max_array_line_len") {
+        if let Some(max_line_length) = number("max_line_length") {
+            config.max_array_line_len = max_line_length;
+        }
+    }
+}
+
+/// The glob of a section header and the properties in the section.
+type Section = (String, Vec<(String, String)>);
+
+/// Whether the file has `root = true` and its sections.
+fn parse(source: &str) -> (bool, Vec<Section>) {
+    let mut root = false;
+    let mut sections: Vec<Section> = vec![];
+    for line in source.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(glob) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']'))
+        {
+            sections.push((glob.to_owned(), vec![]));
+            continue;
+        }
+        let Some((name, value)) = line.split_once(['=', ':']) else { continue };
+        let name = name.trim().to_lowercase();
+        let value = value.trim().to_lowercase();
+        match sections.last_mut() {
+            Some((_, section)) => section.push((name, value)),
+            None if name == "root" => root = value == "true",
+            None => {}
+        }
+    }
+    (root, sections)
+}
+
+/// Whether the section `glob` matches the `path` relative to the directory of the
+/// `.editorconfig`, globs without a `/` match the file name in any directory.
+fn matches(glob: &str, path: &Path) -> bool {
+    let glob = match glob.strip_prefix('/') {
+        Some(glob) => glob.to_owned(),
+        None if glob.contains('/') => glob.to_owned(),
+        None => format!("**/{glob}"),
+    };
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+    expand_braces(&glob).iter().any(|glob| {
+        Pattern::new(glob).is_ok_and(|pattern| pattern.matches_path_with(path, options))
+    })
+}
+
+/// The globs `{a,b}` alternatives stand for, `*.{toml,lock}` is `*.toml` and `*.lock`.
+fn expand_braces(glob: &str) -> Vec<String> {
+    let Some(open) = glob.find('{') else { return vec![glob.to_owned()] };
+    let mut depth = 0;
+    let mut alternatives = vec![];
+    let mut start = open + 1;
+    for (i, c) in glob.char_indices().skip_while(|(i, _)| *i <= open) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                alternatives.push(&glob[start..i]);
+                start = i + 1;
+            }
+            '}' => {
+                alternatives.push(&glob[start..i]);
+                if alternatives.len() == 1 {
+                    // A single alternative is a literal `{...}`.
+                    break;
+                }
+                let (before, after) = (&glob[..open], &glob[i + 1..]);
+                return alternatives
+                    .iter()
+                    .flat_map(|alternative| {
+                        expand_braces(&format!("{before}{alternative}{after}"))
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    vec![glob.to_owned()]
+}
+
+#[cfg(test)]
+mod test {
+    use toml_edit::DocumentMut;
+
+    use super::*;
+    use crate::fmt;
+
+    #[test]
+    fn section_globs() {
+        let manifest = Path::new("crates/foo/Cargo.toml");
+        assert!(matches("*", manifest));
+        assert!(matches("*.toml", manifest));
+        assert!(matches("Cargo.toml", manifest));
+        assert!(matches("*.{toml,lock}", manifest));
+        assert!(matches("{Cargo,Other}.toml", manifest));
+        assert!(matches("crates/**/*.toml", manifest));
+        assert!(matches("crates/**", manifest));
+        assert!(matches("/crates/*/Cargo.toml", manifest));
+        assert!(!matches("/Cargo.toml", manifest));
+        assert!(!matches("crates/*.toml", manifest));
+        assert!(!matches("*.rs", manifest));
+        assert!(!matches("{foo}.toml", manifest));
+    }
+
+    #[test]
+    fn editorconfig_settings() {
+        let path = Path::new("examp/editorconfig/member/Cargo.toml");
+        let properties = properties(path);
+        assert_eq!(properties.get("indent_style").map(String::as_str), Some("tab"));
+        assert_eq!(properties.get("end_of_line").map(String::as_str), Some("crlf"));
+        assert_eq!(properties.get("max_line_length").map(String::as_str), Some("100"));
+        assert!(!properties.contains_key("insert_final_newline"));
+
+        let mut config = Config::default();
+        apply(&mut config, &properties, &[]);
+        assert_eq!(config.indent_string.as_deref(), Some("\t"));
+        assert_eq!(config.crlf, Some(true));
+        assert_eq!(config.max_array_line_len, 100);
+        assert!(config.trailing_newline);
+
+        // Settings in the tomlfmt.toml win.
+        let mut config = "indent_count = 2\ncrlf = false".parse::<Config>().unwrap();
+        apply(&mut config, &properties, &["indent_count".to_owned(), "crlf".to_owned()]);
+        assert_eq!(config.indent_string, None);
+        assert_eq!(config.indent_count, 2);
+        assert_eq!(config.crlf, Some(false));
+
+        let path = Path::new("examp/editorconfig/Cargo.toml");
+        let mut config = Config::default();
+        apply(&mut config, &super::properties(path), &[]);
+        assert_eq!(config.indent_string, None);
+        assert_eq!(config.indent_count, 2);
+        assert_eq!(config.crlf, Some(false));
+        assert!(!config.trailing_newline);
+        assert_eq!(config.max_array_line_len, 100);
+    }
+
+    #[test]
+    fn trailing_whitespace_is_always_trimmed() {
+        let properties = properties(Path::new("examp/editorconfig/Cargo.toml"));
+        assert_eq!(
+            properties.get("trim_trailing_whitespace").map(String::as_str),
+            Some("false")
+        );
+
+        let mut config = Config::default();
+        apply(&mut config, &properties, &[]);
+        let mut toml =
+            "[package] \n# comment \nname = \"foo\"  \n".parse::<DocumentMut>().unwrap();
+        fmt::fmt_toml(&mut toml, &config);
+        assert_eq!(toml.to_string(), "[package]\n# comment\nname = \"foo\"\n");
+    }
+}
@@ -1,6 +1,11 @@
 use std::str::FromStr;
 
-use toml_edit::{Array, DocumentMut, Item, RawString, Table, Value};
+use toml_edit::{
+    Array, Decor, DocumentMut, InlineTable, Item, Key, KeyMut, RawString, Table,
+    TableLike, Value,
+};
+
+use crate::sort::{self, Directive, GroupBy};
 
 #[cfg(target_os = "windows")]
 pub(crate) const DEF_CRLF: bool = true;
@@ -59,11 +64,46 @@ pub(crate) struct Config {
     /// Defaults to 80.
     pub max_array_line_len: usize,
 
+    /// Max line length, key included, before inline dependency tables are broken
+    /// up with newlines, only with `toml_version = "1.1"`.
+    ///
+    /// Defaults to 80.
+    pub max_inline_table_line_len: usize,
+
+    /// The most elements an array may have on a single line, arrays with more are
+    /// broken up with newlines.
+    ///
+    /// Defaults to `None`, which only breaks up arrays by `max_array_line_len`.
+    pub array_max_single_line_items: Option<usize>,
+
+    /// Join arrays written across lines back onto a single line when they have no
+    /// comments and fit in `max_array_line_len`, `false` keeps them across lines.
+    /// Arrays in inline tables follow the same rule.
+    ///
+    /// Defaults to `true`.
+    pub array_collapse: bool,
+
+    /// Pad the keys of each group of key value pairs so their `=` signs line up.
+    ///
+    /// Defaults to `false`.
+    pub align_entries: bool,
+
+    /// Line up the comments at the end of the key value pairs of each group.
+    ///
+    /// Defaults to `false`.
+    pub align_comments: bool,
+
     /// Number of spaces to indent for arrays broken up with newlines.
     ///
     /// Defaults to 4.
     pub indent_count: usize,
 
+    /// The string used for one level of indentation, such as `"\t"`, instead of
+    /// `indent_count` spaces.
+    ///
+    /// Defaults to `None`.
+    pub indent_string: Option<String>,
+
     /// Omit whitespace padding inside inline tables.
     ///
     /// Defaults to `false`.
@@ -76,15 +116,43 @@ pub(crate) struct Config {
 
     /// Are newlines allowed between key value pairs in a table.
     ///
-    /// This must be true for the `--grouped` flag to be used.
+    /// This must be true for the `--grouped` flag to keep blank-line groups.
     /// Defaults to `true`.
     pub key_value_newlines: bool,
 
+    /// What starts a new group of key value pairs with the `--grouped` flag,
+    /// one of `"blank-lines"`, `"comments"` or `"both"`.
+    ///
+    /// Defaults to `"blank-lines"`.
+    pub group_by: GroupBy,
+
+    /// Split dependency tables into groups by source: workspace inherited, path,
+    /// git and then registry dependencies.
+    ///
+    /// Defaults to `false`.
+    pub group_by_source: bool,
+
+    /// Write a generated comment above each group when `group_by_source` is set.
+    ///
+    /// Defaults to `false`.
+    pub source_group_headers: bool,
+
     /// The maximum amount of consecutive blank lines allowed.
     ///
     /// Defaults to `1`.
     pub allowed_blank_lines: usize,
 
+    /// The exact number of blank lines above each table header, or above the
+    /// comments on top of it, except for the first table in the document.
+    ///
+    /// Defaults to `None`, which keeps up to `allowed_blank_lines`.
+    pub blank_lines_before_table: Option<usize>,
+
+    /// The exact number of blank lines between a table header and its first key.
+    ///
+    /// Defaults to `None`, which keeps up to `allowed_blank_lines`.
+    pub blank_lines_after_header: Option<usize>,
+
     /// Use CRLF line endings
     ///
     /// Defaults to `None`, which means use the original file's line endings
@@ -93,33 +161,134 @@ pub(crate) struct Config {
 
     /// The user specified ordering of tables in a document.
     ///
-    /// All unspecified tables will come after these.
+    /// Entries are dotted patterns where `*` matches any one segment, such as
+    /// `target.*.dependencies`. All unspecified tables will come after these, or
+    /// in place of a `"..."` entry.
     pub table_order: Vec<String>,
 
+    /// Place each `[target.<cfg>.<kind>]` table right after the `[<kind>]` table,
+    /// sorted by their cfg expression.
+    ///
+    /// Defaults to `false`.
+    pub target_tables_after_base: bool,
+
+    /// Sort the lints in `[lints]` and `[workspace.lints]`, with the lints that have
+    /// a `priority` first and the tool tables ordered `rust`, `clippy`, `rustdoc`.
+    ///
+    /// Defaults to `false`.
+    pub sort_lints: bool,
+
+    /// Remove duplicate entries when sorting arrays such as `workspace.members`.
+    ///
+    /// Defaults to `false`.
+    pub dedupe_arrays: bool,
+
+    /// Dotted paths of further arrays to sort, such as `package.keywords`, where
+    /// `*` matches any one key.
+    ///
+    /// Defaults to `[]`.
+    pub sort_arrays: Vec<String>,
+
+    /// Only format the document, leaving tables, keys and arrays in their order.
+    ///
+    /// Defaults to `false`.
+    pub no_sort: bool,
+
+    /// Rename deprecated underscore keys like `dev_dependencies` and
+    /// `default_features` to their hyphenated spelling.
+    ///
+    /// Defaults to `false`.
+    pub fix_deprecated_keys: bool,
+
+    /// Remove the quotes around keys that are valid bare keys, like `"serde"`.
+    ///
+    /// Defaults to `false`.
+    pub unquote_keys: bool,
+
+    /// The quotes used for quoted keys and single-line strings, `"basic"` for
+    /// `"..."`, `"literal"` for `'...'` where that keeps the value the same, or
+    /// `"preserve"` to keep them as they are.
+    ///
+    /// Defaults to `"preserve"`.
+    pub quote_style: QuoteStyle,
+
+    /// Write comments with a space after the `#`, `#comment` becomes `# comment`.
+    ///
+    /// Defaults to `false`.
+    pub comment_space: bool,
+
+    /// The TOML version the output must be valid for, `"1.0"` or `"1.1"`.
+    /// Multi-line inline tables are only written for `"1.1"`, except for those
+    /// holding comments which are never collapsed.
+    ///
+    /// Defaults to `"1.0"`.
+    pub toml_version: TomlVersion,
+
     /// Sort feature lists in dependencies.
     pub sort_feature_list: bool,
 }
 
+impl Config {
+    /// The indentation for `depth` levels.
+    fn indent(&self, depth: usize) -> String {
+        match &self.indent_string {
+            Some(indent) => indent.repeat(depth),
+            None => " ".repeat(self.indent_count * depth),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             always_trailing_comma: false,
             multiline_trailing_comma: true,
             max_array_line_len: 80,
+            max_inline_table_line_len: 80,
+            array_max_single_line_items: None,
+            array_collapse: true,
+            align_entries: false,
+            align_comments: false,
             indent_count: 4,
+            indent_string: None,
             space_around_eq: true,
             compact_arrays: false,
             compact_inline_tables: false,
             trailing_newline: true,
             key_value_newlines: true,
+            group_by: GroupBy::default(),
+            group_by_source: false,
+            source_group_headers: false,
             allowed_blank_lines: 1,
+            blank_lines_before_table: None,
+            blank_lines_after_header: None,
             crlf: None,
             table_order: DEF_TABLE_ORDER.iter().map(|&s| s.to_owned()).collect(),
+            target_tables_after_base: false,
+            sort_lints: false,
+            dedupe_arrays: false,
+            sort_arrays: vec![],
+            fix_deprecated_keys: false,
+            no_sort: false,
+            unquote_keys: false,
+            quote_style: QuoteStyle::default(),
+            comment_space: false,
+            toml_version: TomlVersion::default(),
             sort_feature_list: false,
         }
     }
 }
 
+/// The value of the optional count `key`, an error when it is negative.
+fn optional_count(toml: &DocumentMut, key: &str) -> Result<Option<usize>, &'static str> {
+    toml.get(key)
+        .and_then(Item::as_integer)
+        .map(|n| {
+            usize::try_from(n).map_err(|_| "counts in the config must not be negative")
+        })
+        .transpose()
+}
+
 impl FromStr for Config {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -141,8 +310,39 @@ impl FromStr for Config {
                 .get("max_array_line_len")
                 .and_then(Item::as_integer)
                 .unwrap_or(80) as usize,
+            max_inline_table_line_len: toml
+                .get("max_inline_table_line_len")
+                .and_then(Item::as_integer)
+                .unwrap_or(80) as usize,
+            array_max_single_line_items: optional_count(
+                &toml,
+                "array_max_single_line_items",
+            )?,
+            array_collapse: toml
+                .get("array_collapse")
+                .and_then(Item::as_bool)
+                .unwrap_or(true),
+            align_entries: toml
+                .get("align_entries")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            align_comments: toml
+                .get("align_comments")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
             indent_count: toml.get("indent_count").and_then(Item::as_integer).unwrap_or(4)
                 as usize,
+            indent_string: toml
+                .get("indent_string")
+                .and_then(Item::as_str)
+                .map(|indent| {
+                    if indent.chars().all(|c| c == ' ' || c == '\t') {
+                        Ok(indent.to_owned())
+                    } else {
+                        Err("indent_string must only contain spaces and tabs")
+                    }
+                })
+                .transpose()?,
             space_around_eq: toml
                 .get("space_around_eq")
                 .and_then(Item::as_bool)
@@ -163,10 +363,26 @@ impl FromStr for Config {
                 .get("key_value_newlines")
                 .and_then(Item::as_bool)
                 .unwrap_or(true),
+            group_by: toml
+                .get("group_by")
+                .and_then(Item::as_str)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            group_by_source: toml
+                .get("group_by_source")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            source_group_headers: toml
+                .get("source_group_headers")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
             allowed_blank_lines: toml
                 .get("allowed_blank_lines")
                 .and_then(Item::as_integer)
                 .unwrap_or(1) as usize,
+            blank_lines_before_table: optional_count(&toml, "blank_lines_before_table")?,
+            blank_lines_after_header: optional_count(&toml, "blank_lines_after_header")?,
             crlf: toml.get("crlf").and_then(Item::as_bool),
             table_order: toml.get("table_order").and_then(Item::as_array).map_or(
                 DEF_TABLE_ORDER.iter().map(|&s| s.to_owned()).collect(),
@@ -177,6 +393,50 @@ impl FromStr for Config {
                         .collect()
                 },
             ),
+            target_tables_after_base: toml
+                .get("target_tables_after_base")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            dedupe_arrays: toml
+                .get("dedupe_arrays")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            sort_arrays: toml
+                .get("sort_arrays")
+                .and_then(Item::as_array)
+                .map(|arr| {
+                    arr.iter().filter_map(Value::as_str).map(str::to_owned).collect()
+                })
+                .unwrap_or_default(),
+            sort_lints: toml
+                .get("sort_lints")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            no_sort: toml.get("no_sort").and_then(Item::as_bool).unwrap_or_default(),
+            fix_deprecated_keys: toml
+                .get("fix_deprecated_keys")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            unquote_keys: toml
+                .get("unquote_keys")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            quote_style: toml
+                .get("quote_style")
+                .and_then(Item::as_str)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            comment_space: toml
+                .get("comment_space")
+                .and_then(Item::as_bool)
+                .unwrap_or_default(),
+            toml_version: toml
+                .get("toml_version")
+                .and_then(Item::as_str)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
             sort_feature_list: toml
                 .get("sort_feature_list")
                 .and_then(Item::as_bool)
@@ -185,47 +445,172 @@ impl FromStr for Config {
     }
 }
 
+/// The TOML version the formatted output targets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum TomlVersion {
+    /// Inline tables are written on a single line.
+    #[default]
+    V1_0,
+    /// Inline dependency tables longer than `max_inline_table_line_len` are
+    /// written on multiple lines.
+    V1_1,
+}
+
+impl FromStr for TomlVersion {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.0" => Ok(TomlVersion::V1_0),
+            "1.1" => Ok(TomlVersion::V1_1),
+            _ => Err("toml_version must be one of \"1.0\" or \"1.1\""),
+        }
+    }
+}
+
+/// The quotes used for quoted keys and strings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum QuoteStyle {
+    /// Keep the quotes as they are.
+    #[default]
+    Preserve,
+    /// Use `"..."`.
+    Basic,
+    /// Use `'...'` for strings without `'` or control characters, which literal
+    /// strings can't hold.
+    Literal,
+}
+
+impl FromStr for QuoteStyle {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(QuoteStyle::Preserve),
+            "basic" => Ok(QuoteStyle::Basic),
+            "literal" => Ok(QuoteStyle::Literal),
+            _ => Err("quote_style must be one of \"preserve\", \"basic\" or \"literal\""),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Context {
     current_path: Vec<String>,
+    /// The position of the first table header in the document.
+    first_table: Option<isize>,
 }
 
 impl Context {
     fn inside_dependency_section(&self) -> bool {
-        match self.current_path.as_slice() {
-            [section, ..]
-                if (section == "dependencies"
-                    || section == "dev-dependencies"
-                    || section == "build-dependencies") =>
-            {
-                true
-            }
-            [workspace, dependencies, ..]
-                if (workspace == "workspace" && dependencies == "dependencies") =>
-            {
-                true
-            }
-            [section, _target, key, ..]
-                if (section == "target" && key == "dependencies") =>
-            {
-                true
-            }
-            _ => false,
+        inside_dependency_section(&self.current_path)
+    }
+}
+
+/// Whether `path` is inside a dependency table, such as `[dependencies]` or
+/// `[target.'cfg(unix)'.dev-dependencies]`.
+pub(crate) fn inside_dependency_section(path: &[String]) -> bool {
+    match path {
+        [section, ..]
+            if (section == "dependencies"
+                || sort::canonical_key(section) == "dev-dependencies"
+                || sort::canonical_key(section) == "build-dependencies") =>
+        {
+            true
         }
+        [workspace, dependencies, ..]
+            if (workspace == "workspace" && dependencies == "dependencies") =>
+        {
+            true
+        }
+        [section, _target, key, ..]
+            if (section == "target" && sort::MATCHER.heading.contains(&key.as_str())) =>
+        {
+            true
+        }
+        _ => false,
     }
 }
 
 /// Sort an array of cargo features.
 ///
-/// Panics if a feature is not a string.
+/// Features are strings, anything else is sorted after them.
 fn sort_feature_array(array: &mut Array) {
-    array.sort_by_key(|v| {
-        v.as_str().expect("cargo feature should be a string").to_owned()
-    });
+    array.sort_by(sort::cmp_values);
+}
+
+/// Lay out an array on a single line or across multiple lines, `sort_features`
+/// sorts it as a feature list.
+///
+/// Arrays with comments are always written across lines. Other arrays are broken
+/// up when they are longer than `max_array_line_len` or have more than
+/// `array_max_single_line_items` elements, and arrays already written across lines
+/// stay that way unless `array_collapse` is set.
+fn format_array(
+    array: &mut Array,
+    config: &Config,
+    ctx: &Context,
+    depth: usize,
+    sort_features: bool,
+) {
+    if array.has_comments() {
+        // If the array contains comments, we always split the array on multiple
+        // lines to preserve them.
+        format_multi_line_array(array, config, ctx, depth);
+
+        // After formatting the array, all the comments are in the prefix of their
+        // element, we can sort them without risking to separate a comment from
+        // its element.
+        if sort_features {
+            sort_feature_array(array);
+        }
+        return;
+    }
+
+    let is_multi_line = array.is_multi_line();
+
+    // There are no comments, we can reorder the features right away. We must
+    // do it before calling `format_single_line_array()` because
+    // `Array::fmt()` removes whitespaces around the first element, so the
+    // array must already be sorted.
+    if sort_features {
+        sort_feature_array(array);
+    }
+
+    format_single_line_array(array, config, ctx, depth);
+
+    let too_long = array.to_string().len() > config.max_array_line_len;
+    let too_many =
+        config.array_max_single_line_items.is_some_and(|max| array.len() > max);
+    if too_long || too_many || (is_multi_line && !config.array_collapse) {
+        format_multi_line_array(array, config, ctx, depth);
+    }
 }
 
 /// Format an array to fit on a single line.
-fn format_single_line_array(array: &mut Array, config: &Config) {
+fn format_single_line_array(
+    array: &mut Array,
+    config: &Config,
+    ctx: &Context,
+    depth: usize,
+) {
+    // Nested arrays and inline tables are put on a single line first.
+    for value in array.iter_mut() {
+        match value {
+            Value::Array(nested) => {
+                format_single_line_array(nested, config, ctx, depth + 1);
+            }
+            Value::InlineTable(table) => {
+                format_inline_table(
+                    table,
+                    config,
+                    ctx,
+                    depth + 1,
+                    config.indent(depth + 1).len(),
+                );
+            }
+            _ => {}
+        }
+    }
+
     // This method formats the array in a single line with only the necessary
     // whitespaces between elements.
     array.fmt();
@@ -234,13 +619,19 @@ fn format_single_line_array(array: &mut Array, config: &Config) {
     array.set_trailing_comma(config.always_trailing_comma);
 
     // Clean up the prefix and suffix of the array.
-    format_array_decor(array);
+    format_array_decor(array, config);
 }
 
-/// Format an array to fit on multiple lines.
-fn format_multi_line_array(array: &mut Array, config: &Config) {
+/// Format an array to fit on multiple lines, `depth` is the number of arrays and
+/// inline tables it is nested in.
+fn format_multi_line_array(
+    array: &mut Array,
+    config: &Config,
+    ctx: &Context,
+    depth: usize,
+) {
     let newline_pattern = if config.crlf.unwrap_or(DEF_CRLF) { "\r\n" } else { "\n" };
-    let indent = " ".repeat(config.indent_count);
+    let indent = config.indent(depth + 1);
     let newline_and_indent = format!("{newline_pattern}{indent}");
 
     let array_len = array.len();
@@ -260,17 +651,37 @@ fn format_multi_line_array(array: &mut Array, config: &Config) {
         // we move them to the prefix. It allows to have the same behavior whether the
         // element has a trailing comma or not and whether the element is at the end of
         // the array or not.
-        let prefix_comments = value.prefix().trim();
-        let suffix_comments = value.suffix().trim();
+        let prefix_comments = value.prefix().trim().to_owned();
+        let suffix_comments = value.suffix().trim().to_owned();
         let trailing_comments = is_last_item.then_some(&trailing_comments);
 
+        // Nested arrays and inline tables are indented one more level.
+        match value {
+            Value::Array(nested) if nested.is_multi_line() => {
+                format_multi_line_array(nested, config, ctx, depth + 1);
+            }
+            Value::Array(nested) => {
+                format_single_line_array(nested, config, ctx, depth + 1);
+            }
+            Value::InlineTable(table) => {
+                format_inline_table(
+                    table,
+                    config,
+                    ctx,
+                    depth + 1,
+                    config.indent(depth + 1).len(),
+                );
+            }
+            _ => {}
+        }
+
         // Trim each line of comments to enforce the same identation and concatenate them
         // to build the new prefix.
         let mut new_prefix = prefix_comments
             .lines()
             .chain(suffix_comments.lines())
             .chain(trailing_comments.iter().flat_map(|s| s.lines()))
-            .flat_map(|line| [&newline_and_indent, line.trim()])
+            .flat_map(|line| [newline_and_indent.clone(), normalize_line(line, config)])
             .collect::<String>();
 
         // Finally, add a newline and indentation before the element.
@@ -285,12 +696,132 @@ fn format_multi_line_array(array: &mut Array, config: &Config) {
     array.set_trailing_comma(config.multiline_trailing_comma);
 
     // Clean up the prefix and suffix of the array.
-    array.set_trailing(newline_pattern);
-    format_array_decor(array);
+    let closing_indent = config.indent(depth);
+    array.set_trailing(format!("{newline_pattern}{closing_indent}"));
+    format_array_decor(array, config);
+}
+
+/// Trims the whitespace around a line of decor and adds the space after the `#` of a
+/// comment when `comment_space` is set, `#!` and `##` are kept as they are.
+fn normalize_line(line: &str, config: &Config) -> String {
+    let line = line.trim();
+    match line.strip_prefix('#') {
+        Some(comment)
+            if config.comment_space
+                && !comment.is_empty()
+                && !comment.starts_with([' ', '\t', '#', '!']) =>
+        {
+            format!("# {comment}")
+        }
+        _ => line.to_owned(),
+    }
+}
+
+/// Removes the whitespace at the end of each line of a decor prefix, the indentation
+/// on its last line is kept.
+fn normalize_prefix(prefix: &str, config: &Config) -> String {
+    let mut normalized = String::with_capacity(prefix.len());
+    for line in prefix.split_inclusive('\n') {
+        let content = line.trim_end_matches(NEWLINE_CHARS);
+        let newline = &line[content.len()..];
+        if newline.is_empty() && !content.contains('#') {
+            // The indentation before the key
+            normalized.push_str(content);
+            continue;
+        }
+        let content_line = normalize_line(content, config);
+        if !content_line.is_empty() {
+            normalized.push_str(&content[..content.len() - content.trim_start().len()]);
+        }
+        normalized.push_str(&content_line);
+        normalized.push_str(newline);
+    }
+    normalized
+}
+
+/// Removes the whitespace at the end of a decor suffix, keeping the whitespace before
+/// a comment.
+fn normalize_suffix(suffix: &str, config: &Config) -> String {
+    match suffix.find('#') {
+        Some(idx) => {
+            format!("{}{}", &suffix[..idx], normalize_line(&suffix[idx..], config))
+        }
+        None => String::new(),
+    }
+}
+
+/// `prefix` with at most `max` consecutive blank lines.
+fn cap_blank_lines(prefix: &str, max: usize) -> String {
+    let mut capped = String::with_capacity(prefix.len());
+    let mut blank_lines = 0;
+    for line in prefix.split_inclusive('\n') {
+        if line.ends_with('\n') && line.trim().is_empty() {
+            blank_lines += 1;
+            if blank_lines > max {
+                continue;
+            }
+        } else {
+            blank_lines = 0;
+        }
+        capped.push_str(line);
+    }
+    capped
+}
+
+/// `prefix` with exactly `count` blank lines before its first comment.
+fn set_blank_lines(prefix: &str, count: usize, newline_pattern: &str) -> String {
+    let rest = prefix.trim_start_matches(|c: char| c.is_whitespace());
+    // Keep the indentation of the first comment or of the key itself
+    let indent =
+        prefix[..prefix.len() - rest.len()].rsplit('\n').next().unwrap_or_default();
+    format!("{}{indent}{rest}", newline_pattern.repeat(count))
+}
+
+/// The position of the header that is written first.
+pub(crate) fn first_table_position(table: &Table) -> Option<isize> {
+    let mut first = None;
+    for (_, item) in table.iter() {
+        let tables = match item {
+            Item::Table(table) if !table.is_dotted() => vec![table],
+            Item::ArrayOfTables(array) => array.iter().collect(),
+            _ => continue,
+        };
+        for table in tables {
+            let position = table.position().filter(|_| !table.is_implicit());
+            first = [first, position, first_table_position(table)]
+                .into_iter()
+                .flatten()
+                .min();
+        }
+    }
+    first
+}
+
+/// Whether `item` is written as a `[table]` or `[[array]]` header.
+fn is_header(item: &Item) -> bool {
+    item.as_table().is_some_and(|t| !t.is_dotted()) || item.is_array_of_tables()
+}
+
+/// Removes the whitespace around the dots of dotted keys, and inside the brackets
+/// of a table header.
+fn normalize_key_decor(key: &mut KeyMut<'_>, is_header: bool) {
+    let has_whitespace = |decor: &Decor| {
+        [decor.prefix(), decor.suffix()]
+            .into_iter()
+            .flatten()
+            .any(|raw| raw.as_str().is_some_and(|s| !s.is_empty()))
+    };
+    if has_whitespace(key.dotted_decor()) {
+        key.dotted_decor_mut().clear();
+    }
+    if is_header && has_whitespace(key.leaf_decor()) {
+        key.leaf_decor_mut().set_prefix("");
+        key.leaf_decor_mut().set_suffix("");
+    }
 }
 
 /// Format the prefix and suffix of an array.
-fn format_array_decor(array: &mut Array) {
+fn format_array_decor(array: &mut Array, config: &Config) {
     let array_decor = array.decor_mut();
 
     // Always put a single space before the array.
@@ -300,7 +831,7 @@ fn format_array_decor(array: &mut Array) {
     let trailing_comment = array_decor
         .suffix()
         .and_then(|trailing_comment| {
-            let trailing_comment = trailing_comment.as_str()?.trim();
+            let trailing_comment = normalize_line(trailing_comment.as_str()?, config);
 
             // If there is a trailing comment, add a space before it.
             (!trailing_comment.is_empty()).then(|| format!(" {trailing_comment}"))
@@ -309,14 +840,333 @@ fn format_array_decor(array: &mut Array) {
     array_decor.set_suffix(trailing_comment);
 }
 
-fn fmt_value(value: &mut Value, config: &Config, ctx: &mut Context) {
+/// The comments of an inline table, collected before `InlineTable::fmt()` removes
+/// them.
+struct InlineTableComments {
+    /// The comment lines above each entry.
+    leading: Vec<Vec<String>>,
+    /// The comment at the end of each entry's line.
+    end: Vec<Option<String>>,
+    /// The comment lines above the closing brace.
+    closing: Vec<String>,
+}
+
+impl InlineTableComments {
+    fn collect(table: &InlineTable) -> Self {
+        let decor_str = |raw: Option<&RawString>| {
+            raw.and_then(RawString::as_str).unwrap_or_default().to_owned()
+        };
+        // The first line of a prefix holds the end comment of the entry before it,
+        // the comment lines after that belong to the entry itself.
+        let split = |s: &str| -> (Option<String>, Vec<String>) {
+            let (first, rest) = s.find('\n').map_or((s, ""), |idx| s.split_at(idx + 1));
+            let first = first.trim();
+            let lines = rest
+                .lines()
+                .map(str::trim)
+                .filter(|l| l.starts_with('#'))
+                .map(str::to_owned)
+                .collect();
+            (first.starts_with('#').then(|| first.to_owned()), lines)
+        };
+
+        let mut comments = InlineTableComments {
+            leading: Vec::new(),
+            end: Vec::new(),
+            closing: Vec::new(),
+        };
+        for (i, (key, value)) in table.iter().enumerate() {
+            let prefix = entry_prefix(table, key);
+            let (end, mut leading) = split(&prefix);
+            match (i, end) {
+                // A comment right after the opening brace goes above the first entry.
+                (0, Some(end)) => leading.insert(0, end),
+                (_, end) => {
+                    if let Some(prev) = comments.end.last_mut() {
+                        *prev = prev.take().or(end);
+                    }
+                }
+            }
+            comments.leading.push(leading);
+            let suffix = decor_str(last_leaf(value).decor().suffix());
+            let suffix = suffix.trim();
+            comments.end.push(suffix.starts_with('#').then(|| suffix.to_owned()));
+        }
+
+        let (end, closing) = split(&decor_str(Some(table.trailing())));
+        if let Some(prev) = comments.end.last_mut() {
+            *prev = prev.take().or(end);
+        }
+        comments.closing = closing;
+        comments
+    }
+
+    fn is_empty(&self) -> bool {
+        self.leading.iter().all(Vec::is_empty)
+            && self.end.iter().all(Option::is_none)
+            && self.closing.is_empty()
+    }
+}
+
+/// The decor prefix of a table entry, for dotted keys this is the prefix of the
+/// innermost first key like [`sort::entry_prefix`].
+fn entry_prefix(table: &dyn TableLike, key: &str) -> String {
+    let dotted = table.get(key).and_then(Item::as_table_like).filter(|t| t.is_dotted());
+    if let Some(dotted) = dotted {
+        if let Some((first, _)) = dotted.iter().next() {
+            return entry_prefix(dotted, first);
+        }
+    }
+    table
+        .key(key)
+        .and_then(|k| k.leaf_decor().prefix())
+        .and_then(RawString::as_str)
+        .unwrap_or_default()
+        .to_owned()
+}
+
+fn set_entry_prefix(table: &mut dyn TableLike, key: &str, prefix: String) {
+    let dotted =
+        table.get_mut(key).and_then(Item::as_table_like_mut).filter(|t| t.is_dotted());
+    if let Some(dotted) = dotted {
+        let first = dotted.iter().next().map(|(k, _)| k.to_owned());
+        if let Some(first) = first {
+            return set_entry_prefix(dotted, &first, prefix);
+        }
+    }
+    if let Some(mut key) = table.key_mut(key) {
+        key.leaf_decor_mut().set_prefix(prefix);
+    }
+}
+
+/// Removes the whitespace around the keys of a dotted key inside an inline table.
+fn fmt_dotted_keys(dotted: &mut InlineTable) {
+    if !dotted.is_dotted() {
+        return;
+    }
+    for (mut key, value) in dotted.iter_mut() {
+        key.leaf_decor_mut().clear();
+        key.dotted_decor_mut().clear();
+        if let Value::InlineTable(dotted) = value {
+            fmt_dotted_keys(dotted);
+        }
+    }
+}
+
+/// The value written last for an entry, which is nested for dotted keys.
+fn last_leaf(value: &Value) -> &Value {
+    match value {
+        Value::InlineTable(dotted) if dotted.is_dotted() => {
+            dotted.iter().last().map_or(value, |(_, v)| last_leaf(v))
+        }
+        _ => value,
+    }
+}
+
+fn last_leaf_mut(value: &mut Value) -> &mut Value {
+    let is_dotted =
+        matches!(value, Value::InlineTable(t) if t.is_dotted() && !t.is_empty());
+    if !is_dotted {
+        return value;
+    }
     match value {
+        Value::InlineTable(dotted) => {
+            let (_, last) = dotted.iter_mut().last().expect("dotted table is not empty");
+            last_leaf_mut(last)
+        }
+        value => value,
+    }
+}
+
+/// Format an inline table on a single line, or across lines for TOML 1.1 when it
+/// is too long or holds comments, `depth` is the number of arrays and inline tables
+/// it is nested in and `line_start` the width of the key or indentation before it.
+fn format_inline_table(
+    table: &mut InlineTable,
+    config: &Config,
+    ctx: &Context,
+    depth: usize,
+    line_start: usize,
+) {
+    for (key, val) in table.iter_mut() {
+        if let Value::Array(array) = val {
+            // Sorts the features in inline tables.
+            let sort_features = config.sort_feature_list
+                && !config.no_sort
+                && ctx.inside_dependency_section()
+                && key == "features";
+
+            format_array(array, config, ctx, depth, sort_features);
+        }
+    }
+    let comments = InlineTableComments::collect(table);
+
+    table.decor_mut().set_prefix(" ");
+    table.fmt();
+    table.set_trailing("");
+    table.set_trailing_comma(false);
+    // `InlineTable::fmt()` leaves the keys and values inside dotted keys alone.
+    for (_, value) in table.iter_mut() {
+        if let Value::InlineTable(dotted) = value {
+            fmt_dotted_keys(dotted);
+        }
+        last_leaf_mut(value).decor_mut().clear();
+    }
+
+    // Multi-line inline tables are only valid TOML 1.1, but collapsing a table with
+    // comments would lose them and the input must already be TOML 1.1 anyway.
+    let too_long = config.toml_version == TomlVersion::V1_1
+        && ctx.inside_dependency_section()
+        && line_start + table.to_string().len() > config.max_inline_table_line_len;
+    if comments.is_empty() && !too_long || table.is_empty() {
+        return;
+    }
+
+    let newline_pattern = if config.crlf.unwrap_or(DEF_CRLF) { "\r\n" } else { "\n" };
+    let indent = config.indent(depth + 1);
+    let comment_lines = |lines: &[String]| {
+        lines
+            .iter()
+            .map(|l| format!("{indent}{}{newline_pattern}", normalize_line(l, config)))
+            .collect::<String>()
+    };
+    let end_comment = |end: &Option<String>| {
+        end.as_ref()
+            .map(|c| format!(" {}", normalize_line(c, config)))
+            .unwrap_or_default()
+    };
+
+    let keys = table.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+    let last = keys.len() - 1;
+    for (i, key) in keys.iter().enumerate() {
+        let mut prefix =
+            if i == 0 { String::new() } else { end_comment(&comments.end[i - 1]) };
+        prefix.push_str(newline_pattern);
+        prefix.push_str(&comment_lines(&comments.leading[i]));
+        prefix.push_str(&indent);
+        set_entry_prefix(table, key, prefix);
+
+        let Some(value) = table.get_mut(key) else { continue };
+        last_leaf_mut(value).decor_mut().set_suffix("");
+        // Multi-line arrays are indented one more level inside the table.
+        if let Value::Array(array) = value {
+            if array.is_multi_line() {
+                format_multi_line_array(array, config, ctx, depth + 1);
+            }
+        }
+    }
+
+    let mut trailing = String::new();
+    if config.multiline_trailing_comma {
+        trailing.push_str(&end_comment(&comments.end[last]));
+    } else if let Some(value) = table.get_mut(&keys[last]) {
+        last_leaf_mut(value).decor_mut().set_suffix(end_comment(&comments.end[last]));
+    }
+    trailing.push_str(newline_pattern);
+    trailing.push_str(&comment_lines(&comments.closing));
+    trailing.push_str(&config.indent(depth));
+    table.set_trailing(trailing);
+    table.set_trailing_comma(config.multiline_trailing_comma);
+}
+
+/// The repr of the single-line string `value` in the configured quote style, `None`
+/// if `repr` already uses it or can't be written in it.
+fn requote(repr: &str, value: &str, style: QuoteStyle) -> Option<String> {
+    match style {
+        QuoteStyle::Basic if repr.starts_with('\'') && !repr.starts_with("'''") => {
+            let mut basic = String::with_capacity(value.len() + 2);
+            basic.push('"');
+            for c in value.chars() {
+                match c {
+                    '"' => basic.push_str("\\\""),
+                    '\\' => basic.push_str("\\\\"),
+                    c => basic.push(c),
+                }
+            }
+            basic.push('"');
+            Some(basic)
+        }
+        QuoteStyle::Literal
+            if repr.starts_with('"')
+                && !repr.starts_with("\"\"\"")
+                && value.chars().all(|c| c != '\'' && (c == '\t' || !c.is_control())) =>
+        {
+            Some(format!("'{value}'"))
+        }
+        _ => None,
+    }
+}
+
+/// `key` without unneeded quotes and in the configured quote style, `None` if it is
+/// already written that way.
+fn requote_key(key: &Key, config: &Config) -> Option<Key> {
+    let repr = key.as_repr()?.as_raw().as_str()?;
+    if !repr.starts_with(['"', '\'']) {
+        return None;
+    }
+    let is_bare = !key.get().is_empty()
+        && key.get().chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let new_repr = if config.unquote_keys && is_bare {
+        key.get().to_owned()
+    } else {
+        requote(repr, key.get(), config.quote_style)?
+    };
+    let requoted = Key::parse(&new_repr).ok()?.pop().filter(|k| k.get() == key.get())?;
+    Some(
+        requoted
+            .with_leaf_decor(key.leaf_decor().clone())
+            .with_dotted_decor(key.dotted_decor().clone()),
+    )
+}
+
+/// Requotes the strings in `value` and the keys of its inline tables.
+fn requote_value(value: &mut Value, config: &Config) {
+    match value {
+        Value::String(string) => {
+            let requoted = string
+                .as_repr()
+                .and_then(|repr| repr.as_raw().as_str())
+                .and_then(|repr| requote(repr, string.value(), config.quote_style))
+                .and_then(|repr| repr.parse::<Value>().ok())
+                .filter(|v| v.as_str() == Some(string.value()));
+            if let Some(mut requoted) = requoted {
+                *requoted.decor_mut() = value.decor().clone();
+                *value = requoted;
+            }
+        }
         Value::Array(array) => {
-            let has_comments = array.has_comments();
+            for value in array.iter_mut() {
+                requote_value(value, config);
+            }
+        }
+        Value::InlineTable(table) => {
+            let keys = table
+                .iter()
+                .filter_map(|(k, _)| requote_key(table.key(k)?, config))
+                .collect::<Vec<_>>();
+            for key in keys {
+                if let Some(value) = table.get(key.get()).cloned() {
+                    table.insert_formatted(&key, value);
+                }
+            }
+            for (_, value) in table.iter_mut() {
+                requote_value(value, config);
+            }
+        }
+        _ => {}
+    }
+}
 
+/// Format the `value` of a key value pair, `key_width` is the width of the key and
+/// its `=`.
+fn fmt_value(value: &mut Value, config: &Config, ctx: &mut Context, key_width: usize) {
+    requote_value(value, config);
+    match value {
+        Value::Array(array) => {
             // Sorts the feature list in "expanded" representation, where each dependency
             // is in a separate section.
             let sort_features = config.sort_feature_list
+                && !config.no_sort
                 && ctx.inside_dependency_section()
                 && ctx
                     .current_path
@@ -324,74 +1174,10 @@ fn fmt_value(value: &mut Value, config: &Config, ctx: &mut Context) {
                     .map(|name| name == "features")
                     .unwrap_or(false);
 
-            if has_comments {
-                // If the array contains comments, we always split the array on multiple
-                // lines to preserve them.
-                format_multi_line_array(array, config);
-
-                // After formatting the array, all the comments are in the prefix of their
-                // element, we can sort them without risking to separate a comment from
-                // its element.
-                if sort_features {
-                    sort_feature_array(array);
-                }
-            } else {
-                // There are no comments, we can reorder the features right away. We must
-                // do it before calling `format_single_line_array()` because
-                // `Array::fmt()` removes whitespaces around the first element, so the
-                // array must already be sorted.
-                if sort_features {
-                    sort_feature_array(array);
-                }
-
-                // If the array doesn't contain comments, we check if its length on a
-                // single line would fit the current configuration. If it is too long, we
-                // split it on multiple lines.
-                format_single_line_array(array, config);
-
-                if array.to_string().len() > config.max_array_line_len {
-                    format_multi_line_array(array, config);
-                }
-            }
+            format_array(array, config, ctx, 0, sort_features);
         }
         Value::InlineTable(table) => {
-            for (key, val) in table.iter_mut() {
-                if let Value::Array(array) = val {
-                    let is_multi_line = array.is_multi_line();
-
-                    // Sorts the features in inline tables.
-                    let sort_features = config.sort_feature_list
-                        && ctx.inside_dependency_section()
-                        && key == "features";
-
-                    // We preserve the choice of single- vs multi-line from the original
-                    // manifest.
-                    if is_multi_line {
-                        format_multi_line_array(array, config);
-
-                        // After formatting the array, all the comments are in the prefix
-                        // of their element, we can sort them
-                        // without risking to separate a comment from
-                        // its element.
-                        if sort_features {
-                            sort_feature_array(array);
-                        }
-                    } else {
-                        // There are no comments, we can reorder the features right away.
-                        // We must do it before calling
-                        // `format_single_line_array()` because
-                        // `Array::fmt()` removes whitespaces around the first element, so
-                        // the array must already be sorted.
-                        if sort_features {
-                            sort_feature_array(array);
-                        }
-
-                        format_single_line_array(array, config);
-                    }
-                }
-            }
-            table.decor_mut().set_prefix(" ");
-            table.fmt();
+            format_inline_table(table, config, ctx, 0, key_width);
         }
         // Since the above variants have fmt methods we can only ever
         // get here from a headed table (`[header] key = val`)
@@ -412,9 +1198,29 @@ fn fmt_value(value: &mut Value, config: &Config, ctx: &mut Context) {
 fn fmt_table(table: &mut Table, config: &Config, ctx: &mut Context) {
     let newline_pattern = if config.crlf.unwrap_or(DEF_CRLF) { "\r\n" } else { "\n" };
 
+    if Directive::Skip.on_table(table) {
+        // Only the tables nested under a skipped table are formatted
+        for (mut key, item) in table.iter_mut() {
+            if is_header(item) {
+                normalize_key_decor(&mut key, true);
+            }
+            ctx.current_path.push(key.to_string());
+            if let Some(table) = item.as_table_mut().filter(|t| !t.is_dotted()) {
+                fmt_table(table, config, ctx);
+            } else if let Some(array) = item.as_array_of_tables_mut() {
+                for table in array.iter_mut() {
+                    fmt_table(table, config, ctx);
+                }
+            }
+            ctx.current_path.pop();
+        }
+        return;
+    }
+
     // Checks the header decor for blank lines
 
     let current_decor = table.decor().prefix().and_then(RawString::as_str).unwrap_or("");
+    let current_decor = normalize_prefix(current_decor, config);
     let mut new_decor = String::with_capacity(current_decor.len());
 
     let mut num_consecutive_blank_lines = 0;
@@ -435,35 +1241,62 @@ fn fmt_table(table: &mut Table, config: &Config, ctx: &mut Context) {
         }
     }
 
+    if let Some(blank_lines) = config.blank_lines_before_table {
+        if !table.is_dotted() && table.position() != ctx.first_table {
+            new_decor = set_blank_lines(&new_decor, blank_lines, newline_pattern);
+        }
+    }
     table.decor_mut().set_prefix(new_decor);
+    let suffix = table.decor().suffix().and_then(RawString::as_str).unwrap_or_default();
+    let suffix = normalize_suffix(suffix, config);
+    table.decor_mut().set_suffix(suffix);
 
     let keys: Vec<_> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    let mut off = false;
     for key in keys {
+        let prefix = table
+            .get_key_value(&key)
+            .map(|(k, v)| sort::entry_prefix(k, v))
+            .unwrap_or_default();
+        if Directive::On.in_prefix(&prefix) {
+            off = false;
+        } else if Directive::Off.in_prefix(&prefix) {
+            off = true;
+        }
+        // Leave the key value pairs in a `# cargo-sort: off` range as they are
+        if off && !table.get(&key).is_some_and(is_header) {
+            continue;
+        }
+
+        if let Some(requoted) = table.key(&key).and_then(|k| requote_key(k, config)) {
+            let item = table.get_mut(&key).map(std::mem::take).unwrap_or_default();
+            table.insert_formatted(&requoted, item);
+        }
+
         ctx.current_path.push(key.clone());
         let is_value_for_space = table.get(&key).is_some_and(|item| {
             item.is_value() && item.as_inline_table().is_none_or(|t| !t.is_dotted())
         });
 
+        let is_header = table.get(&key).is_some_and(is_header);
         let mut dec = table.key_mut(&key).unwrap();
+        normalize_key_decor(&mut dec, is_header);
         let dec = dec.leaf_decor_mut();
         let prefix = dec.prefix().and_then(RawString::as_str).unwrap_or("");
-        let blank_lines = prefix.lines().filter(|l| !l.starts_with('#')).count();
-
+        let prefix = &normalize_prefix(prefix, config);
+        if dec.prefix().and_then(RawString::as_str) != Some(prefix) {
+            dec.set_prefix(prefix.as_str());
+        }
         // Check each item in the table for blank lines
-        if config.key_value_newlines {
-            if config.allowed_blank_lines < blank_lines {
-                dec.set_prefix(prefix.replacen(
-                    newline_pattern,
-                    "",
-                    blank_lines - config.allowed_blank_lines,
-                ));
-            }
+        let capped = if config.key_value_newlines {
+            cap_blank_lines(prefix, config.allowed_blank_lines)
+        } else if prefix.contains('#') {
+            cap_blank_lines(prefix, 0)
         } else {
-            dec.set_prefix(if prefix.contains('#') {
-                prefix.replacen(newline_pattern, "", blank_lines)
-            } else {
-                "".to_owned()
-            });
+            String::new()
+        };
+        if dec.prefix().and_then(RawString::as_str) != Some(&capped) {
+            dec.set_prefix(capped);
         }
 
         // This is weirdly broken, inserts underscores into `[foo.bar]` table
@@ -479,24 +1312,162 @@ fn fmt_table(table: &mut Table, config: &Config, ctx: &mut Context) {
             ));
         }
 
+        let key_width =
+            table.key(&key).map_or(0, |k| format!("{} =", k.display_repr()).len());
         match table.get_mut(&key).unwrap() {
             Item::Table(table) => {
                 fmt_table(table, config, ctx);
             }
             Item::Value(val) => {
-                fmt_value(val, config, ctx);
+                let suffix = val.decor().suffix().and_then(RawString::as_str);
+                let suffix = normalize_suffix(suffix.unwrap_or_default(), config);
+                val.decor_mut().set_suffix(suffix);
+                fmt_value(val, config, ctx, key_width);
+            }
+            Item::ArrayOfTables(array) => {
+                for table in array.iter_mut() {
+                    fmt_table(table, config, ctx);
+                }
             }
-            Item::ArrayOfTables(_) => {}
             Item::None => {}
         }
         ctx.current_path.pop();
     }
+
+    if let Some(blank_lines) = config.blank_lines_after_header {
+        let first_entry = table.iter().find(|(_, item)| !is_header(item)).map(|(k, _)| k);
+        if let Some(key) = first_entry.map(str::to_owned).filter(|_| !table.is_dotted()) {
+            let prefix = entry_prefix(table, &key);
+            set_entry_prefix(
+                table,
+                &key,
+                set_blank_lines(&prefix, blank_lines, newline_pattern),
+            );
+        }
+    }
+
+    align_table(table, config);
+}
+
+/// A key value pair of a table as it is written on its line, dotted keys are
+/// flattened.
+struct Entry {
+    /// The keys leading to the value, more than one for dotted keys.
+    path: Vec<String>,
+    /// The width of the keys before the `=`.
+    key_width: usize,
+    /// The width of the value, `None` if it spans multiple lines.
+    value_width: Option<usize>,
+    /// The comment after the value.
+    comment: Option<String>,
+}
+
+/// Aligns the `=` signs and the end of line comments of each group of key value
+/// pairs in `table`, a blank line starts a new group.
+fn align_table(table: &mut Table, config: &Config) {
+    if !config.align_entries && !config.align_comments {
+        return;
+    }
+
+    let mut groups: Vec<Vec<Entry>> = vec![vec![]];
+    let mut off = false;
+    for (keys, value) in table.get_values() {
+        let prefix = keys
+            .iter()
+            .filter_map(|k| k.leaf_decor().prefix().and_then(RawString::as_str))
+            .collect::<String>();
+        if Directive::On.in_prefix(&prefix) {
+            off = false;
+        } else if Directive::Off.in_prefix(&prefix) {
+            off = true;
+        }
+        let blank_line = prefix.lines().any(|l| l.trim().is_empty());
+        if (off || blank_line) && groups.last().is_some_and(|g| !g.is_empty()) {
+            groups.push(vec![]);
+        }
+        if off {
+            continue;
+        }
+
+        let key_width =
+            keys.iter().map(|k| k.display_repr().chars().count()).sum::<usize>()
+                + keys.len()
+                - 1;
+        let mut bare_value = value.clone();
+        bare_value.decor_mut().set_suffix("");
+        let bare_value = bare_value.to_string();
+        let comment = value.decor().suffix().and_then(RawString::as_str).map(str::trim);
+        groups.last_mut().unwrap().push(Entry {
+            path: keys.iter().map(|k| k.get().to_owned()).collect(),
+            key_width,
+            value_width: (!bare_value.contains('\n')).then(|| bare_value.chars().count()),
+            comment: comment.filter(|c| c.starts_with('#')).map(str::to_owned),
+        });
+    }
+
+    let space = usize::from(config.space_around_eq);
+    for group in groups {
+        let key_column = group.iter().map(|e| e.key_width).max().unwrap_or_default();
+        let mut widths = Vec::with_capacity(group.len());
+        for entry in &group {
+            let key = dotted_parent_mut(table, &entry.path)
+                .and_then(|parent| parent.key_mut(entry.path.last()?));
+            let Some(mut key) = key else {
+                widths.push(None);
+                continue;
+            };
+            if config.align_entries {
+                let padding = key_column - entry.key_width + space;
+                key.leaf_decor_mut().set_suffix(" ".repeat(padding));
+            }
+            let suffix = key.leaf_decor().suffix().and_then(RawString::as_str);
+            let eq_column = entry.key_width + suffix.map_or(0, |s| s.chars().count());
+            widths.push(entry.value_width.map(|w| eq_column + 1 + w));
+        }
+
+        if !config.align_comments {
+            continue;
+        }
+        let comment_column = group
+            .iter()
+            .zip(&widths)
+            .filter(|(e, _)| e.comment.is_some())
+            .filter_map(|(_, w)| *w)
+            .max()
+            .unwrap_or_default();
+        for (entry, width) in group.iter().zip(widths) {
+            let (Some(comment), Some(width)) = (&entry.comment, width) else { continue };
+            let Some(parent) = dotted_parent_mut(table, &entry.path) else { continue };
+            if let Some(value) = parent.get_mut(entry.path.last().unwrap()) {
+                let padding = " ".repeat(comment_column - width + 1);
+                if let Some(value) = value.as_value_mut() {
+                    value.decor_mut().set_suffix(format!("{padding}{comment}"));
+                }
+            }
+        }
+    }
+}
+
+/// The table holding the last key of a dotted `path`.
+fn dotted_parent_mut<'a>(
+    table: &'a mut dyn TableLike,
+    path: &[String],
+) -> Option<&'a mut dyn TableLike> {
+    match path {
+        [] => None,
+        [_] => Some(table),
+        [first, rest @ ..] => {
+            dotted_parent_mut(table.get_mut(first)?.as_table_like_mut()?, rest)
+        }
+    }
 }
 
 /// Formats a toml `DocumentMut` according to `tomlfmt.toml`.
 pub(crate) fn fmt_toml(toml: &mut DocumentMut, config: &Config) {
-    for (key, item) in toml.as_table_mut().iter_mut() {
-        let mut ctx = Context { current_path: vec![key.to_string()] };
+    let first_table = first_table_position(toml.as_table());
+    for (mut key, item) in toml.as_table_mut().iter_mut() {
+        normalize_key_decor(&mut key, is_header(item));
+        let mut ctx = Context { current_path: vec![key.to_string()], first_table };
         match item {
             Item::ArrayOfTables(table) => {
                 for tab in table.iter_mut() {
@@ -507,11 +1478,21 @@ pub(crate) fn fmt_toml(toml: &mut DocumentMut, config: &Config) {
                 fmt_table(table, config, &mut ctx);
             }
             Item::Value(val) => {
-                fmt_value(val, config, &mut ctx);
+                let prefix = key.leaf_decor().prefix().and_then(RawString::as_str);
+                let prefix = normalize_prefix(prefix.unwrap_or_default(), config);
+                key.leaf_decor_mut().set_prefix(prefix);
+                let suffix = val.decor().suffix().and_then(RawString::as_str);
+                let suffix = normalize_suffix(suffix.unwrap_or_default(), config);
+                val.decor_mut().set_suffix(suffix);
+                let key_width = format!("{} =", key.display_repr()).len();
+                fmt_value(val, config, &mut ctx, key_width);
             }
             Item::None => {}
         }
     }
+    align_table(toml.as_table_mut(), config);
+    let trailing = normalize_prefix(toml.trailing().as_str().unwrap_or_default(), config);
+    toml.set_trailing(trailing);
 
     // TODO:
     // This is TERRIBLE!! Convert the Document to a string only to check it ends with a
@@ -539,7 +1520,19 @@ impl ValueExt for Value {
     }
 }
 
-trait ArrayExt {
+/// Whether a nested array or inline table contains comments.
+fn has_nested_comments(value: &Value) -> bool {
+    match value {
+        Value::Array(array) => array.has_comments(),
+        Value::InlineTable(table) => {
+            !InlineTableComments::collect(table).is_empty()
+                || table.iter().any(|(_, value)| has_nested_comments(value))
+        }
+        _ => false,
+    }
+}
+
+pub(crate) trait ArrayExt {
     /// Whether this array is split on multiple lines.
     fn is_multi_line(&self) -> bool;
 
@@ -561,7 +1554,9 @@ impl ArrayExt for Array {
         // comments.
         self.trailing().as_str().is_some_and(|trailing| !trailing.trim().is_empty())
             || self.iter().any(|value| {
-                !value.prefix().trim().is_empty() || !value.suffix().trim().is_empty()
+                !value.prefix().trim().is_empty()
+                    || !value.suffix().trim().is_empty()
+                    || has_nested_comments(value)
             })
     }
 }
@@ -570,7 +1565,7 @@ impl ArrayExt for Array {
 mod test {
     use std::fs;
 
-    use super::{fmt_toml, Config, DocumentMut};
+    use super::{fmt_toml, Config, DocumentMut, QuoteStyle, TomlVersion};
     use crate::test_utils::assert_eq;
 
     #[test]
@@ -604,6 +1599,14 @@ mod test {
         similar_asserts::assert_eq!(expected, toml.to_string());
     }
 
+    #[test]
+    fn directives() {
+        let input = fs::read_to_string("examp/directives.sorted.toml").unwrap();
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert_eq(input, toml);
+    }
+
     #[test]
     fn array() {
         let input = fs::read_to_string("examp/clippy.toml").unwrap();
@@ -613,6 +1616,286 @@ mod test {
         assert_eq(expected, toml);
     }
 
+    #[test]
+    fn multi_line_inline_tables() {
+        let input = fs::read_to_string("examp/inline_tables.toml").unwrap();
+        let expected = fs::read_to_string("examp/inline_tables.fmt.toml").unwrap();
+        let config = Config { toml_version: TomlVersion::V1_1, ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(&expected, toml);
+
+        // Formatting again must not change anything.
+        let mut toml = expected.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(&expected, toml);
+
+        // Without a trailing comma the end comment of the last key stays on its line.
+        let input = "[dependencies]\nfoo = {\n  version = \"1\", # pinned\n}\n";
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        let config = Config { multiline_trailing_comma: false, ..config };
+        fmt_toml(&mut toml, &config);
+        assert_eq("[dependencies]\nfoo = {\n    version = \"1\" # pinned\n}\n", toml);
+    }
+
+    #[test]
+    fn nested_arrays() {
+        let input = fs::read_to_string("examp/nested_arrays.toml").unwrap();
+        let expected = fs::read_to_string("examp/nested_arrays.fmt.toml").unwrap();
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert_eq(&expected, toml);
+
+        let mut toml = expected.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert_eq(&expected, toml);
+    }
+
+    #[test]
+    fn array_layout_options() {
+        let input = r#"[package]
+keywords = ["a", "b", "c"]
+authors = [
+    "a",
+]
+
+[dependencies]
+foo = { version = "1", features = ["a", "b", "c"] }
+bar = { version = "1", features = [
+    "a",
+] }
+"#;
+
+        // Arrays with too many elements are broken up, in inline tables too.
+        let config = Config { array_max_single_line_items: Some(2), ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        let expected = r#"[package]
+keywords = [
+    "a",
+    "b",
+    "c",
+]
+authors = ["a"]
+
+[dependencies]
+foo = { version = "1", features = [
+    "a",
+    "b",
+    "c",
+] }
+bar = { version = "1", features = ["a"] }
+"#;
+        assert_eq(expected, toml);
+
+        // By default arrays are joined, in inline tables as well.
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        let expected = r#"[package]
+keywords = ["a", "b", "c"]
+authors = ["a"]
+
+[dependencies]
+foo = { version = "1", features = ["a", "b", "c"] }
+bar = { version = "1", features = ["a"] }
+"#;
+        assert_eq(expected, toml);
+
+        // Without collapsing every array keeps its layout.
+        let config = Config { array_collapse: false, ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(input, toml);
+    }
+
+    #[test]
+    fn inline_table_width_counts_the_key() {
+        // The value alone fits in the limit, the key pushes the line over it.
+        let input =
+            "[dependencies]\nsome-long-crate-name = { version = \"1\", path = \"x\" }\n\
+                     a = { version = \"1\", path = \"x\" }\n";
+        let config = Config {
+            toml_version: TomlVersion::V1_1,
+            max_inline_table_line_len: 40,
+            ..Config::default()
+        };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(
+            "[dependencies]\nsome-long-crate-name = {\n    version = \"1\",\n    path = \"x\",\n}\n\
+             a = { version = \"1\", path = \"x\" }\n",
+            toml,
+        );
+    }
+
+    #[test]
+    fn inline_tables_toml_1_0() {
+        // Tables without comments are written on a single line, which TOML 1.0 requires.
+        let input =
+            "[dependencies]\nfoo = {\n    version = \"1\",\n    path = \"foo\",\n}\n";
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert_eq("[dependencies]\nfoo = { version = \"1\", path = \"foo\" }\n", toml);
+
+        assert!("toml_version = \"1.1\"".parse::<Config>().is_ok());
+        assert!("toml_version = \"2\"".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn align_entries_and_comments() {
+        let input = r#"[dependencies]
+serde = "1"
+serde_json = "1" # json
+anyhow.workspace = true
+tokio = { version = "1", features = ["full"] }   # runtime
+
+# A blank line starts a new group.
+a = "1" # a
+bbbbbbbbbb = "2"
+# cargo-sort: off
+zz   =   "9"
+# cargo-sort: on
+c = "3"   # c
+"#;
+        let expected = r#"[dependencies]
+serde            = "1"
+serde_json       = "1"                                    # json
+anyhow.workspace = true
+tokio            = { version = "1", features = ["full"] } # runtime
+
+# A blank line starts a new group.
+a          = "1" # a
+bbbbbbbbbb = "2"
+# cargo-sort: off
+zz   =   "9"
+# cargo-sort: on
+c = "3" # c
+"#;
+        let config =
+            Config { align_entries: true, align_comments: true, ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(expected, &toml);
+
+        fmt_toml(&mut toml, &config);
+        assert_eq(expected, &toml);
+
+        // Only the comments are aligned.
+        let config = Config { align_comments: true, ..Config::default() };
+        let mut toml = "a = 1 # a\nbbb = 2   # b\n".parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq("a = 1   # a\nbbb = 2 # b\n", toml);
+    }
+
+    #[test]
+    fn normalize_quotes() {
+        let input = r#"[package]
+"name" = 'a'
+description = "it's \"quoted\""
+
+[dependencies]
+'tokio' = { 'version' = '1', "features" = ['full', "macros"] }
+"with.dot" = "1"
+multi = """
+line"""
+# cargo-sort: off
+"keep" = 'as is'
+# cargo-sort: on
+
+[target."cfg(unix)".dependencies]
+libc = 'C:\path'
+"#;
+        let basic = r#"[package]
+name = "a"
+description = "it's \"quoted\""
+
+[dependencies]
+tokio = { version = "1", features = ["full", "macros"] }
+"with.dot" = "1"
+multi = """
+line"""
+# cargo-sort: off
+"keep" = 'as is'
+# cargo-sort: on
+
+[target."cfg(unix)".dependencies]
+libc = "C:\\path"
+"#;
+        let literal = r#"[package]
+'name' = 'a'
+description = "it's \"quoted\""
+
+[dependencies]
+'tokio' = { 'version' = '1', 'features' = ['full', 'macros'] }
+'with.dot' = '1'
+multi = """
+line"""
+# cargo-sort: off
+"keep" = 'as is'
+# cargo-sort: on
+
+[target.'cfg(unix)'.dependencies]
+libc = 'C:\path'
+"#;
+        let config = Config {
+            unquote_keys: true,
+            quote_style: QuoteStyle::Basic,
+            ..Config::default()
+        };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(basic, toml);
+
+        let config = Config { quote_style: QuoteStyle::Literal, ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(literal, toml);
+    }
+
+    #[test]
+    fn normalize_whitespace() {
+        let input = "[ package ]   \nname = \"a\"   \nversion = \"1\" #comment   \n#top   \n   \n\
+                     edition = \"2021\"\n\n[ dependencies . foo ] #c\nversion = \"1\"\n\n\
+                     [dependencies]\nbar . workspace = true\nbaz = { a . b = 2 }\n\n\
+                     [[ bin ]]\nname = \"x\"\n##keep\n#trailing   \n";
+        let expected = "[package]\nname = \"a\"\nversion = \"1\" # comment\n# top\n\n\
+                        edition = \"2021\"\n\n[dependencies.foo] # c\nversion = \"1\"\n\n\
+                        [dependencies]\nbar.workspace = true\nbaz = { a.b = 2 }\n\n\
+                        [[bin]]\nname = \"x\"\n##keep\n# trailing\n";
+        let config = Config { comment_space: true, ..Config::default() };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(expected, toml);
+
+        // Without `comment_space` only the whitespace at the end of lines is removed.
+        let mut toml = "a = 1 #a  \n#b  \n".parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert_eq("a = 1 #a\n#b\n", toml);
+    }
+
+    #[test]
+    fn exact_blank_lines() {
+        let input = "\n[package]\n\n\nname = \"a\"\n[dependencies]\nfoo = \"1\"\n\n\n\n\
+                     # about dev deps\n[dev-dependencies]\n\n  # first\n  bar = \"1\"\n\
+                     [[bin]]\nname = \"b\"\n[[bin]]\n\nname = \"c\"\n";
+        let expected = "\n[package]\nname = \"a\"\n\n[dependencies]\nfoo = \"1\"\n\n\
+                        # about dev deps\n[dev-dependencies]\n  # first\n  bar = \"1\"\n\n\
+                        [[bin]]\nname = \"b\"\n\n[[bin]]\nname = \"c\"\n";
+        let config = Config {
+            blank_lines_before_table: Some(1),
+            blank_lines_after_header: Some(0),
+            ..Config::default()
+        };
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &config);
+        assert_eq(expected, toml);
+
+        // Capping blank lines keeps the newline after an indented comment.
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config::default());
+        assert!(toml.to_string().contains("[dev-dependencies]\n\n  # first\n  bar"));
+    }
+
     #[test]
     fn trailing() {
         let input = fs::read_to_string("examp/trailing.toml").unwrap();
@@ -654,7 +1937,7 @@ unexpected_cfgs = { level = "warn", check-cfg = [
     'cfg(custom_cfg_frontend, values("bar"))', # This trailing comment will be on a new line.
     'cfg(custom_cfg_flag)', # This trailing comment will be moved.
 ] }
-# The choice of single- vs multi-line should be preserved.
+# Arrays in inline tables are joined like any other array.
 include = { files = [  "*.rs",   "*.toml"]}
 exclude = { files = [
         "config.rs",
@@ -689,12 +1972,9 @@ unexpected_cfgs = { level = "warn", check-cfg = [
     # This trailing comment will be moved.
     'cfg(custom_cfg_flag)',
 ] }
-# The choice of single- vs multi-line should be preserved.
+# Arrays in inline tables are joined like any other array.
 include = { files = ["*.rs", "*.toml"] }
-exclude = { files = [
-    "config.rs",
-    "tomledit.toml",
-] }
+exclude = { files = ["config.rs", "tomledit.toml"] }
 "#;
         let mut toml = input.parse::<DocumentMut>().unwrap();
         fmt_toml(&mut toml, &Config::default());
@@ -728,12 +2008,9 @@ unexpected_cfgs = { level = "warn", check-cfg = [
     # This trailing comment will be moved.
     'cfg(custom_cfg_flag)'
 ] }
-# The choice of single- vs multi-line should be preserved.
+# Arrays in inline tables are joined like any other array.
 include = { files = ["*.rs", "*.toml"] }
-exclude = { files = [
-    "config.rs",
-    "tomledit.toml"
-] }
+exclude = { files = ["config.rs", "tomledit.toml"] }
 "#;
         let mut toml = input.parse::<DocumentMut>().unwrap();
         let cfg = Config { multiline_trailing_comma: false, ..Config::default() };
@@ -741,6 +2018,18 @@ exclude = { files = [
         similar_asserts::assert_eq!(expected2, toml.to_string());
     }
 
+    #[test]
+    fn non_string_features() {
+        let input =
+            "[dependencies]\nfoo = { version = \"1\", features = [1, \"b\", \"a\"] }\n";
+        let mut toml = input.parse::<DocumentMut>().unwrap();
+        fmt_toml(&mut toml, &Config { sort_feature_list: true, ..Config::default() });
+        assert_eq(
+            "[dependencies]\nfoo = { version = \"1\", features = [\"a\", \"b\", 1] }\n",
+            toml,
+        );
+    }
+
     #[test]
     fn sort_and_format_feature_lists() {
         let config = Config {
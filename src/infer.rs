@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+
+use toml_edit::{Array, DocumentMut, Item, Table, TomlError, Value};
+
+use crate::{
+    fmt::{self, ArrayExt},
+    sort,
+};
+
+/// How often each style choice is made in the scanned manifests.
+#[derive(Default)]
+pub(crate) struct Style {
+    manifests: usize,
+    crlf_manifests: usize,
+    tab_indents: usize,
+    /// The number of array items and inline table keys indented by each number of
+    /// spaces per level.
+    space_indents: BTreeMap<usize, usize>,
+    multi_line_arrays: usize,
+    multi_line_trailing_commas: usize,
+    single_line_arrays: usize,
+    single_line_trailing_commas: usize,
+    spaced_eq: usize,
+    unspaced_eq: usize,
+    max_blank_lines: usize,
+    blank_lines_between_keys: bool,
+    dependency_groups: bool,
+    /// The sum of the relative positions of each top level table, and the number of
+    /// manifests it is in.
+    table_positions: BTreeMap<String, (f64, usize)>,
+    /// The top level tables in the order they were first seen.
+    tables: Vec<String>,
+}
+
+impl Style {
+    /// Count the style choices made in the manifest `raw`.
+    pub(crate) fn scan(&mut self, raw: &str) -> Result<(), TomlError> {
+        let toml = raw.parse::<DocumentMut>()?;
+        self.manifests += 1;
+        if raw.contains("\r\n") {
+            self.crlf_manifests += 1;
+        }
+
+        self.scan_table(toml.as_table());
+
+        let mut tables = toml
+            .iter()
+            .filter_map(|(key, item)| {
+                let position = match item {
+                    Item::Table(table) if !table.is_dotted() => {
+                        [table.position().filter(|_| !table.is_implicit())]
+                            .into_iter()
+                            .chain([fmt::first_table_position(table)])
+                            .flatten()
+                            .min()
+                    }
+                    Item::ArrayOfTables(array) => {
+                        array.iter().filter_map(Table::position).min()
+                    }
+                    _ => None,
+                }?;
+                Some((position, key.to_owned()))
+            })
+            .collect::<Vec<_>>();
+        tables.sort();
+        let last = tables.len().saturating_sub(1).max(1) as f64;
+        for (i, (_, key)) in tables.into_iter().enumerate() {
+            if !self.tables.contains(&key) {
+                self.tables.push(key.clone());
+            }
+            let (sum, count) = self.table_positions.entry(key).or_default();
+            *sum += i as f64 / last;
+            *count += 1;
+        }
+        Ok(())
+    }
+
+    fn scan_table(&mut self, table: &Table) {
+        for (i, (key, item)) in table.iter().enumerate() {
+            match item {
+                Item::Value(value) => {
+                    let key = table.key(key).expect("key of the table");
+                    let prefix = key.leaf_decor().prefix().and_then(|p| p.as_str());
+                    self.scan_blank_lines(prefix);
+                    if i > 0 && prefix.is_some_and(has_blank_line) {
+                        self.blank_lines_between_keys = true;
+                    }
+                    let key_suffix = key.leaf_decor().suffix().and_then(|s| s.as_str());
+                    let value_prefix = value.decor().prefix().and_then(|p| p.as_str());
+                    if key_suffix.is_some_and(|s| s.contains(' '))
+                        || value_prefix.is_some_and(|p| p.contains(' '))
+                    {
+                        self.spaced_eq += 1;
+                    } else {
+                        self.unspaced_eq += 1;
+                    }
+                    self.scan_value(value, 0);
+                }
+                Item::Table(table) => {
+                    if !table.is_implicit() && !table.is_dotted() {
+                        self.scan_blank_lines(
+                            table.decor().prefix().and_then(|p| p.as_str()),
+                        );
+                    }
+                    if sort::MATCHER.heading.contains(&key) {
+                        self.scan_dependencies(table);
+                    }
+                    self.scan_table(table);
+                }
+                Item::ArrayOfTables(array) => {
+                    for table in array.iter() {
+                        self.scan_blank_lines(
+                            table.decor().prefix().and_then(|p| p.as_str()),
+                        );
+                        self.scan_table(table);
+                    }
+                }
+                Item::None => {}
+            }
+        }
+    }
+
+    /// Whether blank lines split the `table` of dependencies into groups.
+    fn scan_dependencies(&mut self, table: &Table) {
+        let groups = table.iter().skip(1).any(|(key, _)| {
+            table
+                .key(key)
+                .and_then(|key| key.leaf_decor().prefix())
+                .and_then(|prefix| prefix.as_str())
+                .is_some_and(has_blank_line)
+        });
+        self.dependency_groups |= groups;
+    }
+
+    /// Count the blank lines in the decor `prefix` of a key or table header.
+    fn scan_blank_lines(&mut self, prefix: Option<&str>) {
+        let Some(prefix) = prefix else { return };
+        let mut lines = prefix.split('\n').collect::<Vec<_>>();
+        // The last line is the indentation of the key or header.
+        lines.pop();
+        let mut blank_lines = 0;
+        for line in lines {
+            if line.trim().is_empty() {
+                blank_lines += 1;
+                self.max_blank_lines = self.max_blank_lines.max(blank_lines);
+            } else {
+                blank_lines = 0;
+            }
+        }
+    }
+
+    /// Count the indentation of an array item or inline table key from its decor
+    /// `prefix`, the item is `depth` levels deep.
+    fn scan_indent(&mut self, prefix: Option<&str>, depth: usize) {
+        let Some((_, indent)) = prefix.and_then(|p| p.rsplit_once('\n')) else { return };
+        if indent.contains('\t') {
+            self.tab_indents += 1;
+        } else if !indent.is_empty() && indent.len() % depth == 0 {
+            *self.space_indents.entry(indent.len() / depth).or_default() += 1;
+        }
+    }
+
+    /// Count the style of a `value` that is `depth` levels inside arrays and inline
+    /// tables.
+    fn scan_value(&mut self, value: &Value, depth: usize) {
+        match value {
+            Value::Array(array) => self.scan_array(array, depth),
+            Value::InlineTable(table) => {
+                for (key, value) in table.iter() {
+                    let key = table.key(key).expect("key of the inline table");
+                    self.scan_indent(
+                        key.leaf_decor().prefix().and_then(|p| p.as_str()),
+                        depth + 1,
+                    );
+                    self.scan_value(value, depth + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn scan_array(&mut self, array: &Array, depth: usize) {
+        if array.is_empty() {
+            return;
+        }
+        if array.is_multi_line() {
+            self.multi_line_arrays += 1;
+            self.multi_line_trailing_commas += usize::from(array.trailing_comma());
+        } else {
+            self.single_line_arrays += 1;
+            self.single_line_trailing_commas += usize::from(array.trailing_comma());
+        }
+        for value in array.iter() {
+            self.scan_indent(value.decor().prefix().and_then(|p| p.as_str()), depth + 1);
+            self.scan_value(value, depth + 1);
+        }
+    }
+
+    /// A tomlfmt.toml with the settings most of the scanned manifests already follow.
+    pub(crate) fn config(&self) -> String {
+        let mut config = format!(
+            "# Inferred from {} manifest{}\n",
+            self.manifests,
+            if self.manifests == 1 { "" } else { "s" }
+        );
+        let mut push = |key: &str, value: String| {
+            config.push_str(&format!("{key} = {value}\n"));
+        };
+
+        push(
+            "always_trailing_comma",
+            (self.single_line_trailing_commas * 2 > self.single_line_arrays).to_string(),
+        );
+        push(
+            "multiline_trailing_comma",
+            (self.multi_line_trailing_commas * 2 >= self.multi_line_arrays).to_string(),
+        );
+        push("space_around_eq", (self.spaced_eq >= self.unspaced_eq).to_string());
+
+        let space_indents = self.space_indents.values().sum::<usize>();
+        if self.tab_indents > space_indents {
+            push("indent_string", "\"\\t\"".to_owned());
+        } else {
+            // The most common indentation, preferring the narrower one on a tie.
+            let indent_count = self
+                .space_indents
+                .iter()
+                .max_by(|(a, a_lines), (b, b_lines)| a_lines.cmp(b_lines).then(b.cmp(a)))
+                .map_or(4, |(indent, _)| *indent);
+            push("indent_count", indent_count.to_string());
+        }
+
+        push("allowed_blank_lines", self.max_blank_lines.max(1).to_string());
+        push("crlf", (self.crlf_manifests * 2 > self.manifests).to_string());
+        push("key_value_newlines", self.blank_lines_between_keys.to_string());
+
+        let mut tables = self.tables.iter().collect::<Vec<_>>();
+        // Stable, so tables with the same average position keep the order they were
+        // first seen in.
+        tables.sort_by(|a, b| {
+            let average = |key| {
+                let (sum, count) = self.table_positions[key];
+                sum / count as f64
+            };
+            average(*a).total_cmp(&average(*b))
+        });
+        if self.dependency_groups {
+            config.push_str(
+                "# blank lines split dependency tables into groups, sort with --grouped to \
+                 keep them\n",
+            );
+        }
+        // The default order puts its tables first and keeps the others in place.
+        let mut default_order = tables.clone();
+        default_order.sort_by_key(|table| {
+            fmt::DEF_TABLE_ORDER
+                .iter()
+                .position(|default| default == table)
+                .unwrap_or(fmt::DEF_TABLE_ORDER.len())
+        });
+        if tables != default_order {
+            config.push_str("table_order = [\n");
+            for table in tables {
+                config.push_str(&format!("    {},\n", Value::from(table.as_str())));
+            }
+            config.push_str("]\n");
+        }
+        config
+    }
+}
+
+/// Whether the decor `prefix` of a key starts with or holds a blank line.
+fn has_blank_line(prefix: &str) -> bool {
+    let mut lines = prefix.split('\n').collect::<Vec<_>>();
+    // The last line is the indentation of the key.
+    lines.pop();
+    lines.iter().any(|line| line.trim().is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::assert_eq;
+
+    #[test]
+    fn infer_config() {
+        let mut style = Style::default();
+        style
+            .scan(
+                "[package]\nname=\"a\"\nkeywords=[\"a\",\"b\",]\nauthors=[\n  \"a\"\n]\n\n\n\
+                 [dev-dependencies]\nfoo=\"1\"\n\n[dependencies]\nbar=\"1\"\n\nbaz=\"1\"\n",
+            )
+            .unwrap();
+        style
+            .scan(
+                "[package]\r\nname=\"b\"\r\nexclude=[\r\n  \"a\",\r\n    \"b\"\r\n]\r\n\r\n\
+                 [features]\r\nstd=[]\r\n\r\n[dev-dependencies]\r\nfoo=\"1\"\r\n",
+            )
+            .unwrap();
+        let expected = r#"# Inferred from 2 manifests
+always_trailing_comma = true
+multiline_trailing_comma = false
+space_around_eq = false
+indent_count = 2
+allowed_blank_lines = 2
+crlf = false
+key_value_newlines = true
+# blank lines split dependency tables into groups, sort with --grouped to keep them
+table_order = [
+    "package",
+    "features",
+    "dev-dependencies",
+    "dependencies",
+]
+"#;
+        assert_eq(expected, style.config());
+
+        // The inferred config is a valid config.
+        assert!(style.config().parse::<fmt::Config>().is_ok());
+    }
+
+    #[test]
+    fn infer_config_from_decor() {
+        // The lines of multi-line strings are neither indentation nor blank lines, and
+        // tables in the default order need no `table_order`.
+        let mut style = Style::default();
+        style
+            .scan(
+                r#"[package]
+name = "a"
+description = """
+  An
+  example
+  of a
+  long text.
+
+
+
+  More.
+"""
+keywords = [
+    "a",
+    [
+        "b",
+    ],
+]
+
+[dependencies]
+foo = "1"
+
+[badges]
+foo = "1"
+"#,
+            )
+            .unwrap();
+        let expected = r#"# Inferred from 1 manifest
+always_trailing_comma = false
+multiline_trailing_comma = true
+space_around_eq = true
+indent_count = 4
+allowed_blank_lines = 1
+crlf = false
+key_value_newlines = false
+"#;
+        assert_eq(expected, style.config());
+    }
+}
@@ -2,9 +2,12 @@ use std::{fmt::Display, fs::read_to_string, io::Write, path::PathBuf};
 
 use fmt::Config;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
-use toml_edit::{DocumentMut, Item};
+use toml_edit::{DocumentMut, Item, TomlError};
 
+mod editorconfig;
 mod fmt;
+mod infer;
+mod semantic;
 mod sort;
 #[cfg(test)]
 mod test_utils;
@@ -31,10 +34,20 @@ pub struct Cli {
     #[arg(short, long, conflicts_with = "check")]
     pub print: bool,
 
+    /// Runs sorting and formatting twice and reports any change made by the second
+    /// run, nothing is written
+    #[arg(long, conflicts_with_all = ["check", "print"])]
+    pub verify: bool,
+
     /// Skips formatting after sorting
     #[arg(short = 'n', long)]
     pub no_format: bool,
 
+    /// Only formats, keeping the order of tables and keys, --check then only
+    /// checks formatting
+    #[arg(long, conflicts_with = "no_format")]
+    pub no_sort: bool,
+
     /// Also returns non-zero exit code if formatting changes
     #[arg(long, requires = "check")]
     pub check_format: bool,
@@ -52,6 +65,16 @@ pub struct Cli {
     #[arg(short, long, value_delimiter = ',')]
     pub order: Vec<String>,
 
+    /// Rewrite deprecated underscore keys (`dev_dependencies`, `default_features`...)
+    /// to their hyphenated spelling
+    #[arg(long)]
+    pub fix_deprecated: bool,
+
+    /// Prints a tomlfmt.toml that matches the style of the manifests, with
+    /// --workspace of every workspace member, nothing is sorted or written
+    #[arg(long, conflicts_with_all = ["check", "print", "verify"])]
+    pub infer_config: bool,
+
     /// Path to a custom config file (tomlfmt.toml)
     #[arg(long, value_name = "PATH")]
     pub config: Option<PathBuf>,
@@ -85,6 +108,10 @@ struct ProcessedToml {
     is_sorted: bool,
     is_formatted: bool,
     final_output: String,
+    /// Deprecated keys and duplicate array entries left in the output.
+    warnings: Vec<String>,
+    /// The `sort_arrays` arrays that were not sorted in the input.
+    unsorted_arrays: Vec<String>,
 }
 
 fn process_toml(
@@ -93,9 +120,21 @@ fn process_toml(
     no_format: bool,
     check_format: bool,
     config: &Config,
-) -> ProcessedToml {
-    let mut sorted =
-        sort::sort_toml(toml_raw, sort::MATCHER, grouped, &config.table_order);
+) -> Result<ProcessedToml, TomlError> {
+    let (mut sorted, warnings, unsorted_arrays) = if config.no_sort {
+        let mut toml = toml_raw.parse::<DocumentMut>()?;
+        let warnings = sort::deprecated_keys(&mut toml, false);
+        (toml, warnings, vec![])
+    } else {
+        let mut sorted = sort::sort_toml(toml_raw, sort::MATCHER, grouped, config)?;
+        let mut warnings = sort::deprecated_keys(&mut sorted, false);
+        warnings.extend(sort::duplicate_array_entries(&sorted, &sort::MATCHER, config));
+        let unsorted_arrays = toml_raw
+            .parse::<DocumentMut>()
+            .map(|toml| sort::unsorted_arrays(&toml, config))
+            .unwrap_or_default();
+        (sorted, warnings, unsorted_arrays)
+    };
     let sorted_only = sorted.to_string();
     // sort_toml/toml_edit always outputs LF, but input may have CRLF. Normalize
     // the raw input for comparison's sake only (formatting checking/fix is
@@ -119,14 +158,49 @@ fn process_toml(
             final_output
         };
 
-    ProcessedToml { is_sorted, is_formatted, final_output }
+    Ok(ProcessedToml { is_sorted, is_formatted, final_output, warnings, unsorted_arrays })
+}
+
+/// The lines that differ between `first` and `second`, without the lines both
+/// start and end with.
+fn minimal_diff(first: &str, second: &str) -> String {
+    let first = first.lines().collect::<Vec<_>>();
+    let second = second.lines().collect::<Vec<_>>();
+    let start = first.iter().zip(&second).take_while(|(a, b)| a == b).count();
+    let end = first[start..]
+        .iter()
+        .rev()
+        .zip(second[start..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut diff = format!("@@ line {} @@\n", start + 1);
+    for line in &first[start..first.len() - end] {
+        diff.push_str(&format!("-{line}\n"));
+    }
+    for line in &second[start..second.len() - end] {
+        diff.push_str(&format!("+{line}\n"));
+    }
+    diff
 }
 
-fn check_toml(path: &str, cli: &Cli, config: &Config) -> IoResult<bool> {
-    let mut path = PathBuf::from(path);
+/// The path of the manifest in `dir`, or `dir` itself when it is a file.
+fn manifest_path(dir: &str) -> PathBuf {
+    let path = PathBuf::from(dir);
     if path.is_dir() {
-        path.push("Cargo.toml");
+        path.join("Cargo.toml")
+    } else {
+        path
     }
+}
+
+fn check_toml(
+    path: &str,
+    cli: &Cli,
+    config: &Config,
+    config_keys: &[String],
+) -> IoResult<bool> {
+    let path = manifest_path(path);
 
     let krate = path.components().nth_back(1).ok_or("No crate folder found")?.as_os_str();
 
@@ -138,28 +212,67 @@ fn check_toml(path: &str, cli: &Cli, config: &Config) -> IoResult<bool> {
     let crlf = toml_raw.contains("\r\n");
 
     let mut config = config.clone();
+    editorconfig::apply(&mut config, &editorconfig::properties(&path), config_keys);
     if config.crlf.is_none() {
         config.crlf = Some(crlf);
     }
 
     let result =
-        process_toml(&toml_raw, cli.grouped, cli.no_format, cli.check_format, &config);
+        process_toml(&toml_raw, cli.grouped, cli.no_format, cli.check_format, &config)
+            .map_err(|e| format!("{} is not valid toml: {e}", path.display()))?;
 
     if cli.print {
         print!("{}", result.final_output);
         return Ok(true);
     }
 
+    if cli.verify {
+        let second = process_toml(
+            &result.final_output,
+            cli.grouped,
+            cli.no_format,
+            cli.check_format,
+            &config,
+        )
+        .map_err(|e| {
+            format!(
+                "Sorting Cargo.toml for {} produced invalid toml, please file an issue: {e}",
+                krate.to_string_lossy()
+            )
+        })?;
+        if second.final_output != result.final_output {
+            write_red(
+                "error: ",
+                format!(
+                    "Sorting Cargo.toml for {} again changes it, please file an issue\n{}",
+                    krate.to_string_lossy(),
+                    minimal_diff(&result.final_output, &second.final_output)
+                ),
+            )?;
+            return Ok(false);
+        }
+        return Ok(true);
+    }
+
     if cli.check {
+        // With nothing sorted formatting is all there is to check
+        let check_format = cli.check_format || config.no_sort;
+
         if !result.is_sorted {
             write_red(
                 "error: ",
                 format!("Dependencies for {} are not sorted", krate.to_string_lossy()),
             )?;
         }
+        for path in &result.unsorted_arrays {
+            write_red(
+                "error: ",
+                format!("`{path}` in {} is not sorted", krate.to_string_lossy()),
+            )?;
+        }
 
         if !result.is_formatted {
-            if cli.check_format {
+            if check_format {
                 write_red(
                     "error: ",
                     format!(
@@ -178,11 +291,25 @@ fn check_toml(path: &str, cli: &Cli, config: &Config) -> IoResult<bool> {
             }
         }
 
-        return Ok(result.is_sorted && (!cli.check_format || result.is_formatted));
+        for msg in &result.warnings {
+            write_yellow("warning: ", format!("{msg} in {}", krate.to_string_lossy()))?;
+        }
+
+        return Ok(result.is_sorted && (!check_format || result.is_formatted));
     }
 
     let has_changes = toml_raw != result.final_output;
     if has_changes {
+        if let Err(path) =
+            semantic::check_equivalent(&toml_raw, &result.final_output, &config)
+        {
+            return Err(format!(
+                "Cargo.toml for {} was not written, sorting changed `{path}`; please file an \
+                 issue",
+                krate.to_string_lossy()
+            )
+            .into());
+        }
         std::fs::write(&path, &result.final_output)?;
         write_green(
             "Finished: ",
@@ -272,10 +399,23 @@ fn _main() -> IoResult<()> {
         }
     }
 
-    let mut config = if let Some(config_path) = &cli.config {
+    if cli.infer_config {
+        let mut style = infer::Style::default();
+        for path in &filtered_matches {
+            let path = manifest_path(path);
+            let toml_raw = read_to_string(&path)
+                .map_err(|_| format!("No file found at: {}", path.display()))?;
+            style
+                .scan(&toml_raw)
+                .map_err(|e| format!("{} is not valid toml: {e}", path.display()))?;
+        }
+        print!("{}", style.config());
+        return Ok(());
+    }
+
+    let config_raw = if let Some(config_path) = &cli.config {
         read_to_string(config_path)
             .map_err(|_| format!("config file not found: {}", config_path.display()))?
-            .parse::<Config>()?
     } else {
         let mut config_path = cwd.clone();
         config_path.push("tomlfmt.toml");
@@ -286,15 +426,28 @@ fn _main() -> IoResult<()> {
                 read_to_string(&config_path)
             })
             .unwrap_or_default()
-            .parse::<Config>()?
     };
+    let mut config = config_raw.parse::<Config>()?;
+    // The settings of the config file take precedence over `.editorconfig` files.
+    let config_keys = config_raw
+        .parse::<DocumentMut>()
+        .map(|toml| toml.iter().map(|(key, _)| key.to_owned()).collect::<Vec<_>>())
+        .unwrap_or_default();
 
     if !cli.order.is_empty() {
         config.table_order = cli.order.clone();
     }
+    if cli.fix_deprecated {
+        config.fix_deprecated_keys = true;
+    }
+    if cli.no_sort {
+        config.no_sort = true;
+    }
 
     let mut flag = true;
-    for sorted in filtered_matches.iter().map(|path| check_toml(path, &cli, &config)) {
+    for sorted in
+        filtered_matches.iter().map(|path| check_toml(path, &cli, &config, &config_keys))
+    {
         if !(sorted?) {
             flag = false;
         }
@@ -355,6 +508,92 @@ mod tests {
         assert_eq!(config.indent_count, 2);
     }
 
+    #[test]
+    fn group_by_config_parsing() {
+        let config: Config = "group_by = \"comments\"".parse().unwrap();
+        assert_eq!(config.group_by, sort::GroupBy::Comments);
+        assert_eq!(Config::default().group_by, sort::GroupBy::BlankLines);
+        assert!("group_by = \"tables\"".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn negative_counts_are_rejected() {
+        let config: Config = "blank_lines_before_table = 2".parse().unwrap();
+        assert_eq!(config.blank_lines_before_table, Some(2));
+        assert!("blank_lines_before_table = -1".parse::<Config>().is_err());
+        assert!("blank_lines_after_header = -1".parse::<Config>().is_err());
+        assert!("array_max_single_line_items = -3".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn check_deprecated_keys() {
+        let toml = "[dev_dependencies]\nfoo = \"1\"\n";
+        let result = process_toml(toml, false, false, false, &Config::default()).unwrap();
+        assert_eq!(result.warnings.len(), 1);
+
+        let config = Config { fix_deprecated_keys: true, ..Config::default() };
+        let result = process_toml(toml, false, false, false, &config).unwrap();
+        assert!(result.warnings.is_empty());
+        assert_eq!(result.final_output, "[dev-dependencies]\nfoo = \"1\"\n");
+    }
+
+    #[test]
+    fn no_sort_only_formats() {
+        let toml = "[dependencies]\nfoo=\"1\"\nbar = \"1\"\n\n[package]\nname = \"x\"\n";
+        let config = Config { no_sort: true, ..Config::default() };
+        let result = process_toml(toml, false, false, false, &config).unwrap();
+        assert!(result.is_sorted);
+        assert!(!result.is_formatted);
+        assert_eq!(
+            result.final_output,
+            "[dependencies]\nfoo = \"1\"\nbar = \"1\"\n\n[package]\nname = \"x\"\n"
+        );
+
+        // Feature lists keep their order too.
+        let toml =
+            "[dependencies]\nz = { version = \"1\", features = [\"b\", \"a\"] }\n\n\
+                    [dependencies.y]\nversion = \"1\"\nfeatures = [\"d\", \"c\"]\n";
+        let config =
+            Config { no_sort: true, sort_feature_list: true, ..Config::default() };
+        let result = process_toml(toml, false, false, true, &config).unwrap();
+        assert!(result.is_sorted);
+        assert!(result.is_formatted);
+        assert_eq!(result.final_output, toml);
+    }
+
+    #[test]
+    fn minimal_diff() {
+        let diff = super::minimal_diff("a\nb\nc\nd\n", "a\nc\nb\nd\n");
+        assert_eq!(diff, "@@ line 2 @@\n-b\n-c\n+c\n+b\n");
+        let diff = super::minimal_diff("a\nb\n", "a\n");
+        assert_eq!(diff, "@@ line 2 @@\n-b\n");
+    }
+
+    #[test]
+    fn sort_and_format_reach_fixed_point() {
+        let files = ["examp", "regressions", "fixtures/valid"]
+            .into_iter()
+            .flat_map(|dir| std::fs::read_dir(dir).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"));
+        let config = Config::default();
+        for path in files {
+            let input = read_to_string(&path).unwrap();
+            for grouped in [false, true] {
+                let first = process_toml(&input, grouped, false, false, &config).unwrap();
+                let second =
+                    process_toml(&first.final_output, grouped, false, false, &config)
+                        .unwrap();
+                assert!(
+                    first.final_output == second.final_output,
+                    "{} changes when sorted again:\n{}",
+                    path.display(),
+                    super::minimal_diff(&first.final_output, &second.final_output)
+                );
+            }
+        }
+    }
+
     #[test]
     fn config_error_on_missing_file() {
         let result = read_to_string("nonexistent_config.toml");
@@ -365,7 +604,7 @@ mod tests {
     fn check_unsorted() {
         let toml = "[dependencies]\nfoo = \"1\"\nbar = \"1\"\n";
         let config = Config::default();
-        let result = process_toml(toml, false, false, false, &config);
+        let result = process_toml(toml, false, false, false, &config).unwrap();
         assert!(!result.is_sorted);
         assert!(result.is_formatted);
     }
@@ -375,7 +614,7 @@ mod tests {
         // Sorted deps, but missing space around '=' so formatting differs
         let toml = "[dependencies]\nbar=\"1\"\nfoo=\"1\"\n";
         let config = Config::default();
-        let result = process_toml(toml, false, false, false, &config);
+        let result = process_toml(toml, false, false, false, &config).unwrap();
         assert!(result.is_sorted);
         assert!(!result.is_formatted);
     }
@@ -384,32 +623,81 @@ mod tests {
     fn sorted_unformatted_no_check_format() {
         let toml = "[dependencies]\nbar=\"1\"\nfoo=\"1\"\n";
         let config = Config::default();
-        let result = process_toml(toml, false, false, true, &config);
+        let result = process_toml(toml, false, false, true, &config).unwrap();
         assert!(result.is_sorted);
         assert!(!result.is_formatted);
     }
 
+    #[test]
+    fn misaligned_is_unformatted() {
+        let toml = "[dependencies]\nbar = \"1\"\nfoo_bar = \"1\"\n";
+        let config = Config { align_entries: true, ..Config::default() };
+        let result = process_toml(toml, false, false, true, &config).unwrap();
+        assert!(result.is_sorted);
+        assert!(!result.is_formatted);
+        assert_eq!(
+            result.final_output,
+            "[dependencies]\nbar     = \"1\"\nfoo_bar = \"1\"\n"
+        );
+    }
+
+    #[test]
+    fn indent_string_and_crlf_round_trip() {
+        let toml = r#"[package]
+name = "a"
+keywords = ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbbbbbbbb", "cccccccccccccccccccccccc", "dddd"]
+
+[dependencies]
+foo = { version = "1", features = [
+  # c
+  "x",
+] }
+bar = { version = "1", # pinned
+  path = "bar" }
+"#;
+        for crlf in [false, true] {
+            for indent in ["\t", "  ", "\t "] {
+                let config = Config {
+                    crlf: Some(crlf),
+                    indent_string: Some(indent.to_owned()),
+                    toml_version: fmt::TomlVersion::V1_1,
+                    ..Config::default()
+                };
+                let input =
+                    if crlf { toml.replace('\n', "\r\n") } else { toml.to_owned() };
+                let first = process_toml(&input, false, false, false, &config).unwrap();
+                let output = first.final_output;
+                assert_eq!(crlf, output.contains("\r\n"));
+                if crlf {
+                    assert_eq!(
+                        output.matches('\n').count(),
+                        output.matches("\r\n").count()
+                    );
+                }
+                let lines = output.lines().collect::<Vec<_>>();
+                assert!(
+                    lines.contains(&format!("{indent}\"aaaaaaaaaaaaaaaa\",").as_str())
+                );
+                assert!(lines.contains(&format!("{indent}# c").as_str()));
+                assert!(lines.contains(&format!("{indent}path = \"bar\",").as_str()));
+
+                let second = process_toml(&output, false, false, true, &config).unwrap();
+                assert!(second.is_formatted);
+                assert_eq!(output, second.final_output);
+            }
+        }
+
+        assert!("indent_string = \"-\"".parse::<Config>().is_err());
+    }
+
     #[test]
     fn sorted_with_crlf_detected_as_sorted() {
         let toml = "[dependencies]\r\nbar = \"1\"\r\nfoo = \"1\"\r\n";
         let config = Config::default();
-        let result = process_toml(toml, false, false, false, &config);
+        let result = process_toml(toml, false, false, false, &config).unwrap();
         assert!(
             result.is_sorted,
             "CRLF file with sorted deps should be detected as sorted"
         );
     }
 }
-
-// #[test]
-// fn fuzzy_fail() {
-//     for file in std::fs::read_dir("out/default/crashes").unwrap() {
-//         let path = file.unwrap().path();
-//         println!("{}", path.display());
-//         let s = read_to_string(&path).unwrap().replace("\r", "");
-//         let mut toml = sort::sort_toml(&s, sort::MATCHER, false);
-//         fmt::fmt_toml(&mut toml, &fmt::Config::default());
-//         print!("{}", s);
-//         s.parse::<DocumentMut>().unwrap();
-//     }
-// }
@@ -0,0 +1,473 @@
+use std::collections::BTreeMap;
+
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::{
+    fmt::{self, Config},
+    sort,
+};
+
+/// The data a toml document holds, without its formatting or the order of keys.
+#[derive(Debug, Clone, PartialEq)]
+enum Semantic {
+    String(String),
+    Integer(i64),
+    /// The bits of the float, so `nan` is equal to itself.
+    Float(u64),
+    Boolean(bool),
+    Datetime(String),
+    Array(Vec<Semantic>),
+    Table(BTreeMap<String, Semantic>),
+}
+
+impl Semantic {
+    fn from_table(table: &Table) -> Self {
+        Semantic::Table(
+            table
+                .iter()
+                .filter_map(|(key, item)| {
+                    Some((key.to_owned(), Semantic::from_item(item)?))
+                })
+                .collect(),
+        )
+    }
+
+    fn from_item(item: &Item) -> Option<Self> {
+        match item {
+            Item::None => None,
+            Item::Value(value) => Some(Semantic::from_value(value)),
+            Item::Table(table) => Some(Semantic::from_table(table)),
+            Item::ArrayOfTables(arr) => {
+                Some(Semantic::Array(arr.iter().map(Semantic::from_table).collect()))
+            }
+        }
+    }
+
+    fn from_value(value: &Value) -> Self {
+        match value {
+            Value::String(s) => Semantic::String(s.value().clone()),
+            Value::Integer(i) => Semantic::Integer(*i.value()),
+            Value::Float(f) => Semantic::Float(f.value().to_bits()),
+            Value::Boolean(b) => Semantic::Boolean(*b.value()),
+            Value::Datetime(dt) => Semantic::Datetime(dt.value().to_string()),
+            Value::Array(arr) => {
+                Semantic::Array(arr.iter().map(Semantic::from_value).collect())
+            }
+            Value::InlineTable(table) => Semantic::Table(
+                table
+                    .iter()
+                    .map(|(key, value)| (key.to_owned(), Semantic::from_value(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Whether cargo-sort may reorder the array at `path`, its elements are compared
+/// without regard to order.
+fn is_reordered(path: &[String], patterns: &[String], config: &Config) -> bool {
+    patterns.iter().any(|pat| sort::dotted_path_matches(pat, path))
+        || config.sort_feature_list
+            && path.last().is_some_and(|key| key == "features")
+            && fmt::inside_dependency_section(path)
+}
+
+/// Checks that `output` holds the same data as `input` once the changes
+/// cargo-sort is asked to make are accounted for.
+///
+/// Returns the dotted path of the first value that differs.
+pub(crate) fn check_equivalent(
+    input: &str,
+    output: &str,
+    config: &Config,
+) -> Result<(), String> {
+    let mut input =
+        input.parse::<DocumentMut>().map_err(|_| "<input does not parse>".to_owned())?;
+    if config.fix_deprecated_keys {
+        sort::deprecated_keys(&mut input, true);
+    }
+    let output = output
+        .parse::<DocumentMut>()
+        .map_err(|e| format!("<output does not parse: {}>", e.message()))?;
+
+    let patterns = sort::sorted_array_patterns(&sort::MATCHER, config);
+    let mut path = vec![];
+    diff(
+        &Semantic::from_table(input.as_table()),
+        &Semantic::from_table(output.as_table()),
+        &mut path,
+        &|path| is_reordered(path, &patterns, config),
+        config.dedupe_arrays,
+    )
+    .then_some(())
+    .ok_or_else(|| path.join("."))
+}
+
+/// Whether `a` and `b` are the same, if not `path` is left pointing at the
+/// difference.
+fn diff(
+    a: &Semantic,
+    b: &Semantic,
+    path: &mut Vec<String>,
+    unordered: &dyn Fn(&[String]) -> bool,
+    dedupe: bool,
+) -> bool {
+    match (a, b) {
+        (Semantic::Table(a), Semantic::Table(b)) => {
+            for key in a.keys().chain(b.keys().filter(|key| !a.contains_key(*key))) {
+                path.push(key.clone());
+                let same = match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff(a, b, path, unordered, dedupe),
+                    _ => false,
+                };
+                if !same {
+                    return false;
+                }
+                path.pop();
+            }
+            true
+        }
+        (Semantic::Array(a), Semantic::Array(b)) if unordered(path) => {
+            let contains = |arr: &[Semantic], value: &Semantic| arr.contains(value);
+            if dedupe {
+                a.iter().all(|v| contains(b, v)) && b.iter().all(|v| contains(a, v))
+            } else {
+                let mut rest = b.clone();
+                a.len() == b.len()
+                    && a.iter().all(|v| {
+                        rest.iter()
+                            .position(|r| r == v)
+                            .map(|idx| rest.remove(idx))
+                            .is_some()
+                    })
+            }
+        }
+        (Semantic::Array(a), Semantic::Array(b)) => {
+            if a.len() != b.len() {
+                return false;
+            }
+            for (idx, (a, b)) in a.iter().zip(b).enumerate() {
+                path.push(idx.to_string());
+                if !diff(a, b, path, unordered, dedupe) {
+                    return false;
+                }
+                path.pop();
+            }
+            true
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Runs `input` through sorting and formatting twice, the properties every input
+/// must have: no panic, output that holds the same values and a second run that
+/// changes nothing.
+///
+/// Inputs that are not valid toml are ignored.
+#[cfg(test)]
+fn check_pipeline(input: &str, grouped: bool, config: &Config) -> Result<(), String> {
+    let run = |input: &str| -> Result<String, String> {
+        let mut toml = sort::sort_toml(input, sort::MATCHER, grouped, config)
+            .map_err(|e| format!("output does not parse: {e}"))?;
+        fmt::fmt_toml(&mut toml, config);
+        Ok(toml.to_string())
+    };
+    if input.parse::<DocumentMut>().is_err() {
+        return Ok(());
+    }
+
+    let first = run(input)?;
+    check_equivalent(input, &first, config)
+        .map_err(|path| format!("sorting changed `{path}`:\n{first}"))?;
+    let second = run(&first)?;
+    if first != second {
+        return Err(format!("sorting again changed the output:\n{first}\n---\n{second}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use serde_json::Value as Json;
+    use toml_edit::{DocumentMut, Item, Table, Value};
+
+    use crate::{fmt, fmt::Config, sort};
+
+    /// Compares a toml value with its toml-test JSON encoding.
+    fn same_as_json(item: &Item, json: &Json) -> bool {
+        match item {
+            Item::None => false,
+            Item::Value(value) => value_same_as_json(value, json),
+            Item::Table(table) => table_same_as_json(table, json),
+            Item::ArrayOfTables(arr) => json_array(json).is_some_and(|json| {
+                arr.len() == json.len()
+                    && arr.iter().zip(json).all(|(t, json)| table_same_as_json(t, json))
+            }),
+        }
+    }
+
+    fn table_same_as_json(table: &Table, json: &Json) -> bool {
+        let Some(json) = json.as_object() else { return false };
+        table.len() == json.len()
+            && table.iter().all(|(key, item)| {
+                json.get(key).is_some_and(|json| same_as_json(item, json))
+            })
+    }
+
+    /// Arrays are plain JSON arrays, or `{"type": "array", "value": [..]}` in
+    /// older versions of toml-test.
+    fn json_array(json: &Json) -> Option<&Vec<Json>> {
+        json.as_array().or_else(|| {
+            (json.get("type")? == "array").then(|| json.get("value")?.as_array())?
+        })
+    }
+
+    fn value_same_as_json(value: &Value, json: &Json) -> bool {
+        let scalar = || Some((json.get("type")?.as_str()?, json.get("value")?.as_str()?));
+        match value {
+            Value::Array(arr) => json_array(json).is_some_and(|json| {
+                arr.len() == json.len()
+                    && arr.iter().zip(json).all(|(v, json)| value_same_as_json(v, json))
+            }),
+            Value::InlineTable(table) => json.as_object().is_some_and(|json| {
+                table.len() == json.len()
+                    && table.iter().all(|(key, value)| {
+                        json.get(key).is_some_and(|json| value_same_as_json(value, json))
+                    })
+            }),
+            Value::String(s) => scalar() == Some(("string", s.value())),
+            Value::Integer(i) => scalar().is_some_and(|(ty, v)| {
+                ty == "integer" && v.parse::<i64>().ok() == Some(*i.value())
+            }),
+            Value::Float(f) => scalar().is_some_and(|(ty, v)| {
+                let expected = match v.trim_start_matches('+') {
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    "nan" | "-nan" => f64::NAN,
+                    v => v.parse().unwrap_or(f64::NAN),
+                };
+                let f = *f.value();
+                ty == "float" && (f == expected || (f.is_nan() && expected.is_nan()))
+            }),
+            Value::Boolean(b) => scalar() == Some(("bool", &b.value().to_string())),
+            Value::Datetime(dt) => scalar().is_some_and(|(ty, v)| {
+                (ty.starts_with("date") || ty.starts_with("time")) && {
+                    let normalize = |s: &str| s.replace(' ', "T").to_lowercase();
+                    normalize(v) == normalize(&dt.value().to_string())
+                }
+            }),
+        }
+    }
+
+    #[test]
+    fn toml_test_valid_corpus() {
+        let config = Config::default();
+        let mut files = fs::read_dir("fixtures/valid")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect::<Vec<_>>();
+        files.sort();
+        assert!(!files.is_empty());
+
+        for path in files {
+            let input = fs::read_to_string(&path).unwrap();
+            let json = fs::read_to_string(path.with_extension("json")).unwrap();
+            let json = serde_json::from_str::<Json>(&json).unwrap();
+
+            let mut toml =
+                sort::sort_toml(&input, sort::MATCHER, false, &config).unwrap();
+            fmt::fmt_toml(&mut toml, &config);
+            let output = toml.to_string();
+
+            let reparsed = output.parse::<DocumentMut>().unwrap_or_else(|e| {
+                panic!("{} does not parse after sorting: {e}\n{output}", path.display())
+            });
+            assert!(
+                table_same_as_json(reparsed.as_table(), &json),
+                "{} changed when sorting:\n{output}",
+                path.display()
+            );
+            assert_eq!(super::check_equivalent(&input, &output, &config), Ok(()));
+        }
+    }
+
+    /// A small xorshift generator, so the generated documents are the same on
+    /// every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.next(items.len())]
+        }
+    }
+
+    /// Generates a manifest like document made of the tables and keys cargo-sort
+    /// treats specially, with comments, blank lines and odd values mixed in.
+    fn generate(rng: &mut Rng) -> String {
+        const HEADERS: &[&str] = &[
+            "[package]",
+            "[dependencies]",
+            "[dev-dependencies]",
+            "[build_dependencies]",
+            "[target.'cfg(unix)'.dependencies]",
+            "[target.\"cfg(windows)\".dev-dependencies]",
+            "[workspace]",
+            "[workspace.dependencies]",
+            "[features]",
+            "[profile.release]",
+            "[profile.dev.package.foo]",
+            "[profile.custom]",
+            "[lints.rust]",
+            "[lints.clippy]",
+            "[workspace.lints.rustdoc]",
+            "[dependencies.foo]",
+            "[dependencies.foo.bar]",
+            "[[dependencies.foo.baz]]",
+            "[[bin]]",
+            "[[example]]",
+            "[lib]",
+            "[package.metadata.docs.rs]",
+        ];
+        const KEYS: &[&str] = &[
+            "a",
+            "b",
+            "zeta",
+            "serde",
+            "tokio",
+            "anyhow.workspace",
+            "x.y.z",
+            "\"quoted\"",
+            "members",
+            "exclude",
+            "features",
+            "default_features",
+            "crate_type",
+            "priority",
+            "level",
+            "version",
+            "path",
+            "git",
+        ];
+        const VALUES: &[&str] = &[
+            "\"1\"",
+            "1",
+            "-3.5",
+            "nan",
+            "true",
+            "1979-05-27T07:32:00Z",
+            "[]",
+            "[\"b\", \"a\", \"b\"]",
+            "[1, \"a\", [2], { x = 1 }]",
+            "[\n    # lead\n    \"b\", # end\n    \"a\",\n]",
+            "{ version = \"1\", features = [\"z\", \"a\"] }",
+            "{ path = \"../x\", default_features = false }",
+            "{ workspace = true }",
+            "{ git = \"https://example.com\" }",
+            "{ level = \"warn\", priority = -1 }",
+            "{ # why\n  version = \"1\", # pin\n  a.b = [\n    \"x\",\n  ],\n}",
+        ];
+        const DECOR: &[&str] =
+            &["", "", "", "\n", "# comment\n", "\n# group\n", "# cargo-sort: pin-top\n"];
+
+        let mut doc = String::new();
+        for _ in 0..rng.next(6) {
+            doc.push_str(rng.pick(DECOR));
+            doc.push_str(&format!("{} = {}\n", rng.pick(KEYS), rng.pick(VALUES)));
+        }
+        for _ in 0..rng.next(8) {
+            doc.push_str(rng.pick(DECOR));
+            doc.push_str(rng.pick(HEADERS));
+            doc.push('\n');
+            for _ in 0..rng.next(6) {
+                doc.push_str(rng.pick(DECOR));
+                doc.push_str(&format!("{} = {}\n", rng.pick(KEYS), rng.pick(VALUES)));
+            }
+        }
+        doc
+    }
+
+    #[test]
+    fn generated_documents() {
+        let configs = [
+            Config::default(),
+            Config {
+                group_by: sort::GroupBy::Both,
+                group_by_source: true,
+                source_group_headers: true,
+                target_tables_after_base: true,
+                sort_lints: true,
+                sort_feature_list: true,
+                fix_deprecated_keys: true,
+                dedupe_arrays: true,
+                sort_arrays: vec!["*".to_owned(), "package.*".to_owned()],
+                toml_version: fmt::TomlVersion::V1_1,
+                max_inline_table_line_len: 30,
+                align_entries: true,
+                align_comments: true,
+                unquote_keys: true,
+                comment_space: true,
+                blank_lines_before_table: Some(1),
+                blank_lines_after_header: Some(0),
+                quote_style: fmt::QuoteStyle::Literal,
+                array_max_single_line_items: Some(3),
+                array_collapse: false,
+                indent_string: Some("\t".to_owned()),
+                ..Config::default()
+            },
+        ];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut valid = 0;
+        for _ in 0..1000 {
+            let input = generate(&mut rng);
+            if input.parse::<DocumentMut>().is_err() {
+                continue;
+            }
+            valid += 1;
+            for config in &configs {
+                for grouped in [false, true] {
+                    if let Err(e) = super::check_pipeline(&input, grouped, config) {
+                        panic!("input:\n{input}\n---\n{e}");
+                    }
+                }
+            }
+        }
+        assert!(valid > 100, "only {valid} generated documents were valid toml");
+    }
+
+    #[test]
+    fn detects_changes() {
+        let config = Config::default();
+        let input =
+            "[package]\nname = \"a\"\n\n[dependencies]\nfoo = { version = \"1\" }\n";
+        assert_eq!(super::check_equivalent(input, input, &config), Ok(()));
+
+        let output =
+            "[package]\nname = \"a\"\n\n[dependencies]\nfoo = { version = \"2\" }\n";
+        let diff = super::check_equivalent(input, output, &config);
+        assert_eq!(diff, Err("dependencies.foo.version".to_owned()));
+
+        let output = "[package]\nname = \"a\"\n";
+        let diff = super::check_equivalent(input, output, &config);
+        assert_eq!(diff, Err("dependencies".to_owned()));
+
+        assert!(super::check_equivalent(input, "[package", &config).is_err());
+
+        // Sorted arrays may change order, others may not
+        let input = "[workspace]\nmembers = [\"b\", \"a\"]\nexclude = [\"b\", \"a\"]\n\
+                     default-members = [\"b\", \"a\"]\n";
+        let output = "[workspace]\nmembers = [\"a\", \"b\"]\nexclude = [\"a\", \"b\"]\n\
+                      default-members = [\"a\", \"b\"]\n";
+        let diff = super::check_equivalent(input, output, &config);
+        assert_eq!(diff, Err("workspace.default-members.0".to_owned()));
+    }
+}
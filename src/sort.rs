@@ -1,6 +1,11 @@
-use std::{cmp::Ordering, collections::BTreeMap, iter::FromIterator};
+use std::{cmp::Ordering, collections::BTreeMap, str::FromStr};
 
-use toml_edit::{Array, Decor, DocumentMut, Item, RawString, Table, Value};
+use toml_edit::{
+    Array, DocumentMut, InlineTable, Item, Key, RawString, Table, TableLike, TomlError,
+    Value,
+};
+
+use crate::fmt::{self, Config};
 
 /// Leading string for combining keys such as
 /// `[target.'cfg(target_os="linux")'.dependencies]` in Cargo.toml files.
@@ -23,6 +28,24 @@ const TARGET: &str = "target";
 /// ```
 type TargetTablePaths = BTreeMap<String, Vec<Vec<String>>>;
 
+/// Leading string of `[profile.release]` tables in Cargo.toml files.
+const PROFILE: &str = "profile";
+
+/// The profiles built into cargo, they are sorted before custom profiles in this
+/// order.
+const BUILTIN_PROFILES: &[&str] = &["dev", "release", "test", "bench"];
+
+/// The tables holding `[<tables>.<tool>]` lint tables.
+const LINT_TABLES: &[&[&str]] = &[&["lints"], &["workspace", "lints"]];
+
+/// The lint tools in the order their `[lints.<tool>]` tables are sorted in, other
+/// tools come after these.
+const LINT_TOOLS: &[&str] = &["rust", "clippy", "rustdoc"];
+
+/// The `table_order` entry that marks where the tables not matched by any other
+/// entry go.
+const UNLISTED: &str = "...";
+
 /// Each `Matcher` field when matched to a heading or key token
 /// will be matched with `.contains()`.
 #[derive(Debug)]
@@ -35,7 +58,13 @@ pub(crate) struct Matcher<'a> {
 }
 
 pub(crate) const MATCHER: Matcher<'_> = Matcher {
-    heading: &["dependencies", "dev-dependencies", "build-dependencies"],
+    heading: &[
+        "dependencies",
+        "dev-dependencies",
+        "build-dependencies",
+        "dev_dependencies",
+        "build_dependencies",
+    ],
     heading_key: &[
         ("workspace", "members"),
         ("workspace", "exclude"),
@@ -45,6 +74,148 @@ pub(crate) const MATCHER: Matcher<'_> = Matcher {
     ],
 };
 
+/// Deprecated underscore spellings of manifest keys and their hyphenated
+/// replacement, newer cargo editions reject the underscore spellings.
+pub(crate) const DEPRECATED_KEYS: &[(&str, &str)] = &[
+    ("dev_dependencies", "dev-dependencies"),
+    ("build_dependencies", "build-dependencies"),
+    ("default_features", "default-features"),
+    ("crate_type", "crate-type"),
+];
+
+/// The hyphenated spelling of `key` if it is a deprecated underscore spelling.
+pub(crate) fn canonical_key(key: &str) -> &str {
+    DEPRECATED_KEYS.iter().find(|(from, _)| *from == key).map_or(key, |(_, to)| to)
+}
+
+/// What starts a new group of key value pairs when sorting with `--grouped`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GroupBy {
+    /// A key preceded by one or more blank lines starts a new group.
+    #[default]
+    BlankLines,
+    /// A key preceded by a standalone comment line starts a new group, the
+    /// comment stays at the top of the group as its header.
+    Comments,
+    /// Either a blank line or a standalone comment line starts a new group.
+    Both,
+}
+
+impl GroupBy {
+    /// Whether a key with the given decor prefix starts a new group.
+    fn starts_group(self, prefix: &str) -> bool {
+        let blank_lines = prefix.lines().any(|l| !l.trim_start().starts_with('#'));
+        // Directives such as `pin-top` act on the key, they do not head a group.
+        let comments = prefix
+            .lines()
+            .any(|l| l.trim_start().starts_with('#') && Directive::parse(l).is_none());
+        match self {
+            GroupBy::BlankLines => blank_lines,
+            GroupBy::Comments => comments,
+            GroupBy::Both => blank_lines || comments,
+        }
+    }
+}
+
+impl FromStr for GroupBy {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blank-lines" => Ok(GroupBy::BlankLines),
+            "comments" => Ok(GroupBy::Comments),
+            "both" => Ok(GroupBy::Both),
+            _ => Err("group_by must be one of \"blank-lines\", \"comments\" or \"both\""),
+        }
+    }
+}
+
+/// A `# cargo-sort: <directive>` comment that tells cargo-sort to leave part of a
+/// manifest alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Directive {
+    /// `# cargo-sort: skip` above a table header, the key value pairs of the table
+    /// are neither sorted nor formatted.
+    Skip,
+    /// `# cargo-sort: off` above a key, this key and the ones after it are left as
+    /// they are until a `# cargo-sort: on`.
+    Off,
+    /// `# cargo-sort: on` above a key ends an `off` range.
+    On,
+    /// `# cargo-sort: pin-top` above a key keeps it at the top of its table, or
+    /// its group with `--grouped`.
+    PinTop,
+}
+
+impl Directive {
+    fn parse(line: &str) -> Option<Self> {
+        let directive =
+            line.trim().strip_prefix('#')?.trim().strip_prefix("cargo-sort:")?;
+        match directive.trim() {
+            "skip" => Some(Directive::Skip),
+            "off" => Some(Directive::Off),
+            "on" => Some(Directive::On),
+            "pin-top" => Some(Directive::PinTop),
+            _ => None,
+        }
+    }
+
+    /// Whether a decor prefix contains this directive.
+    pub(crate) fn in_prefix(self, prefix: &str) -> bool {
+        prefix.lines().any(|line| Directive::parse(line) == Some(self))
+    }
+
+    /// Whether this directive is above the header of `table`.
+    pub(crate) fn on_table(self, table: &Table) -> bool {
+        self.in_prefix(table.decor().prefix().and_then(RawString::as_str).unwrap_or(""))
+    }
+}
+
+/// Where a dependency comes from, used to split dependency tables into groups.
+///
+/// The groups are written out in the order of the variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DependencySource {
+    /// `foo.workspace = true`
+    Workspace,
+    /// `foo = { path = "../foo" }`
+    Path,
+    /// `foo = { git = "https://..." }`
+    Git,
+    /// `foo = "1"`
+    Registry,
+}
+
+impl DependencySource {
+    const ALL: [DependencySource; 4] = [
+        DependencySource::Workspace,
+        DependencySource::Path,
+        DependencySource::Git,
+        DependencySource::Registry,
+    ];
+
+    fn of(item: &Item) -> Self {
+        if item.get("workspace").and_then(Item::as_bool) == Some(true) {
+            DependencySource::Workspace
+        } else if item.get("path").is_some() {
+            DependencySource::Path
+        } else if item.get("git").is_some() {
+            DependencySource::Git
+        } else {
+            DependencySource::Registry
+        }
+    }
+
+    /// The comment written above the group when `source_group_headers` is set.
+    fn header(self) -> &'static str {
+        match self {
+            DependencySource::Workspace => "# Workspace dependencies",
+            DependencySource::Path => "# Path dependencies",
+            DependencySource::Git => "# Git dependencies",
+            DependencySource::Registry => "# Registry dependencies",
+        }
+    }
+}
+
 /// A state machine to track collection of headings.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum Heading {
@@ -56,15 +227,20 @@ enum Heading {
     Complete(Vec<String>),
 }
 
-/// Returns a sorted toml `DocumentMut`.
+/// Returns a sorted toml `DocumentMut`, or the error if `input` is not valid toml.
 pub(crate) fn sort_toml(
     input: &str,
     matcher: Matcher<'_>,
     group: bool,
-    ordering: &[String],
-) -> DocumentMut {
-    let mut ordering = ordering.to_owned();
-    let mut toml = input.parse::<DocumentMut>().unwrap();
+    config: &Config,
+) -> Result<DocumentMut, TomlError> {
+    let group = group.then_some(config.group_by);
+    let ordering = &config.table_order;
+    let mut toml = input.parse::<DocumentMut>()?;
+    if config.fix_deprecated_keys {
+        deprecated_keys(&mut toml, true);
+    }
+    let leading_blank_lines = leading_blank_lines(&mut toml);
     // This takes care of `[workspace] members = [...]`
     for (heading, key) in matcher.heading_key {
         // Since this `&mut toml[&heading]` is like
@@ -72,13 +248,13 @@ pub(crate) fn sort_toml(
         // know the heading is there already
         if toml.as_table().contains_key(heading) {
             if let Item::Table(table) = &mut toml[heading] {
-                if table.contains_key(key) {
+                if table.contains_key(key) && !Directive::Skip.on_table(table) {
                     match &mut table[key] {
                         Item::Value(Value::Array(arr)) => {
-                            sort_array(arr);
+                            sort_array(arr, config.dedupe_arrays);
                         }
                         Item::Table(table) => {
-                            sort_table(table, group);
+                            sort_dependencies(table, group, config);
                         }
                         _ => {}
                     }
@@ -87,6 +263,30 @@ pub(crate) fn sort_toml(
         }
     }
 
+    if !config.sort_arrays.is_empty() {
+        let patterns = &config.sort_arrays;
+        sort_arrays_at_paths(
+            toml.as_table_mut(),
+            &mut vec![],
+            patterns,
+            config.dedupe_arrays,
+        );
+    }
+
+    if config.sort_lints {
+        for path in LINT_TABLES {
+            if let Some(lints) =
+                item_at_path(toml.as_table_mut(), path).and_then(Item::as_table_mut)
+            {
+                for (_, tool) in lints.iter_mut() {
+                    if let Some(tool) = tool.as_table_mut() {
+                        sort_lint_table(tool);
+                    }
+                }
+            }
+        }
+    }
+
     let mut first_table = None;
     let mut heading_order: BTreeMap<_, Vec<Heading>> = BTreeMap::new();
     for (idx, (head, item)) in toml.as_table_mut().iter_mut().enumerate() {
@@ -108,9 +308,6 @@ pub(crate) fn sort_toml(
         }
 
         if !matcher.heading.contains(&item_key) && target_tables.is_empty() {
-            if !ordering.contains(&head.to_owned()) && !ordering.is_empty() {
-                ordering.push(head.to_owned());
-            }
             continue;
         }
         match item {
@@ -129,21 +326,107 @@ pub(crate) fn sort_toml(
 
                 gather_headings(table, headings, 1);
                 headings.sort();
-                sort_table(table, group);
-                sort_nested_table(table, &target_tables);
+                if item_key == TARGET {
+                    sort_table(table, group);
+                    sort_nested_table(table, &target_tables, group, config);
+                } else {
+                    sort_dependencies(table, group, config);
+                }
             }
             Item::None => continue,
             _ => {}
         }
     }
 
-    if ordering.is_empty() {
+    if ordering.is_empty() && !config.target_tables_after_base {
         sort_lexicographical(first_table, &heading_order, &mut toml);
+        sort_among_positions(&mut toml, &[PROFILE], profile_sort_key);
     } else {
-        sort_by_ordering(&ordering, &heading_order, &mut toml);
+        sort_by_ordering(
+            ordering,
+            matcher.heading,
+            config.target_tables_after_base,
+            &mut toml,
+        );
     }
+    if config.sort_lints {
+        for path in LINT_TABLES {
+            let tool_idx = path.len();
+            sort_among_positions(&mut toml, path, |path| {
+                let tool = path.get(tool_idx).map_or("", String::as_str);
+                let rank = LINT_TOOLS.iter().position(|&t| t == tool);
+                (rank.unwrap_or(LINT_TOOLS.len()), path[tool_idx..].join("."))
+            });
+        }
+    }
+    restore_leading_blank_lines(&mut toml, leading_blank_lines);
 
-    toml
+    Ok(toml)
+}
+
+/// Calls `f` with every table that is written with a `[header]` or `[[header]]`.
+fn for_each_header(table: &mut Table, f: &mut dyn FnMut(&mut Table)) {
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => {
+                if !table.is_implicit() && !table.is_dotted() {
+                    f(table);
+                }
+                for_each_header(table, f);
+            }
+            Item::ArrayOfTables(array) => {
+                for table in array.iter_mut() {
+                    f(table);
+                    for_each_header(table, f);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The blank lines above each table header, in the order of the document.
+fn leading_blank_lines(toml: &mut DocumentMut) -> Vec<String> {
+    let mut headers = vec![];
+    for_each_header(toml.as_table_mut(), &mut |table| {
+        let prefix = table.decor().prefix().and_then(RawString::as_str).unwrap_or("");
+        let (blank, _) = split_blank_lines(prefix);
+        headers.push((table.position(), blank.to_owned()));
+    });
+    headers.sort_by_key(|(position, _)| *position);
+    headers.into_iter().map(|(_, blank)| blank).collect()
+}
+
+/// Gives each table header the blank lines that were at its place in the input,
+/// the comments above a header move with it.
+fn restore_leading_blank_lines(toml: &mut DocumentMut, blank_lines: Vec<String>) {
+    let mut positions = vec![];
+    for_each_header(toml.as_table_mut(), &mut |table| positions.push(table.position()));
+    let mut order = (0..positions.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| positions[i]);
+    let mut blank_for = vec![None; positions.len()];
+    for (blank, i) in blank_lines.into_iter().zip(order) {
+        blank_for[i] = Some(blank);
+    }
+
+    let mut blank_for = blank_for.into_iter();
+    for_each_header(toml.as_table_mut(), &mut |table| {
+        let Some(Some(blank)) = blank_for.next() else { return };
+        let prefix = table.decor().prefix().and_then(RawString::as_str).unwrap_or("");
+        let (_, rest) = split_blank_lines(prefix);
+        let prefix = format!("{blank}{rest}");
+        table.decor_mut().set_prefix(prefix);
+    });
+}
+
+/// Splits the blank lines at the start of a header prefix from the rest.
+fn split_blank_lines(prefix: &str) -> (&str, &str) {
+    let blank = prefix
+        .split_inclusive('\n')
+        .take_while(|line| line.ends_with('\n') && line.trim().is_empty())
+        .map(str::len)
+        .sum();
+    prefix.split_at(blank)
 }
 
 fn nested_tables_with_key<'a>(
@@ -164,80 +447,435 @@ fn nested_tables_with_key<'a>(
     }
 }
 
-fn sort_array(arr: &mut Array) {
-    let mut all_strings = true;
-    let trailing = arr.trailing().clone();
-    let trailing_comma = arr.trailing_comma();
+/// The whitespace and comments around one element of an array.
+///
+/// `leading` is the part of the prefix after the line the previous element ended
+/// on, `end` is the rest of the line after the element and its comma.
+#[derive(Debug, Clone, Default)]
+struct ArrayDecor {
+    leading: String,
+    suffix: String,
+    end: String,
+}
 
-    let mut arr_copy = arr.iter().cloned().collect::<Vec<_>>();
-    arr_copy.sort_by(|a, b| match (a, b) {
+/// Splits the prefix of an array element into the end of the previous line and
+/// the rest.
+fn split_first_line(s: &str) -> (&str, &str) {
+    s.find('\n').map_or(("", s), |idx| s.split_at(idx + 1))
+}
+
+fn has_comment(s: &str) -> bool {
+    s.contains('#')
+}
+
+/// Orders array values, strings first then numbers, booleans and everything else
+/// by their textual representation.
+pub(crate) fn cmp_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::String(_) => 0,
+            Value::Integer(_) => 1,
+            Value::Float(_) => 2,
+            Value::Boolean(_) => 3,
+            _ => 4,
+        }
+    }
+    rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
         (Value::String(a), Value::String(b)) => a.value().cmp(b.value()),
-        _ => {
-            all_strings = false;
-            Ordering::Equal
+        (Value::Integer(a), Value::Integer(b)) => a.value().cmp(b.value()),
+        (Value::Float(a), Value::Float(b)) => a.value().total_cmp(b.value()),
+        (Value::Boolean(a), Value::Boolean(b)) => a.value().cmp(b.value()),
+        _ => value_repr(a).cmp(&value_repr(b)),
+    })
+}
+
+/// The value as written, without the whitespace and comments around it.
+fn value_repr(value: &Value) -> String {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    value.to_string()
+}
+
+/// Sorts an array, keeping the comments above an element and the comment on the
+/// line it ends on together with the element.
+///
+/// Comments before the first element on the line of the `[` and comments below
+/// the last element stay where they are. With `dedupe` only the first of equal
+/// elements is kept, the comments of the removed elements are dropped.
+fn sort_array(arr: &mut Array, dedupe: bool) {
+    if arr.is_empty() {
+        return;
+    }
+
+    let trailing_comma = arr.trailing_comma();
+    let last = arr.len() - 1;
+    let mut values = Vec::with_capacity(arr.len());
+    let mut slots = Vec::with_capacity(arr.len());
+    let mut opening = String::new();
+    for (idx, value) in arr.iter().enumerate() {
+        let prefix = value.decor().prefix().and_then(RawString::as_str).unwrap_or("");
+        let (head, leading) = split_first_line(prefix);
+        if idx == 0 {
+            opening = head.to_owned();
+        } else {
+            let prev: &mut ArrayDecor = &mut slots[idx - 1];
+            prev.end = head.to_owned();
         }
-    });
-    if all_strings {
-        *arr = Array::from_iter(arr_copy);
+        let suffix = value.decor().suffix().and_then(RawString::as_str).unwrap_or("");
+        slots.push(ArrayDecor {
+            leading: leading.to_owned(),
+            // Without a trailing comma everything up to the `]` is in the suffix
+            suffix: if idx == last && !trailing_comma { "" } else { suffix }.to_owned(),
+            end: String::new(),
+        });
+        let mut value = value.clone();
+        value.decor_mut().clear();
+        values.push(value);
+    }
+    let after = if trailing_comma {
+        arr.trailing().as_str().unwrap_or("").to_owned()
+    } else {
+        arr.get(last)
+            .and_then(|v| v.decor().suffix())
+            .and_then(RawString::as_str)
+            .unwrap_or("")
+            .to_owned()
+    };
+    let (end, closing) = split_first_line(&after);
+    slots[last].end = end.to_owned();
+    let closing = closing.to_owned();
+
+    // The comments move with their element, the plain whitespace stays in place.
+    let mut entries = values.into_iter().zip(slots.iter().cloned()).collect::<Vec<_>>();
+    entries.sort_by(|(a, _), (b, _)| cmp_values(a, b));
+    if dedupe {
+        entries.dedup_by(|(a, _), (b, _)| cmp_values(a, b) == Ordering::Equal);
     }
 
-    arr.set_trailing(trailing);
-    arr.set_trailing_comma(trailing_comma);
+    let len = entries.len();
+    arr.clear();
+    let mut prev_end = opening;
+    for (idx, (mut value, decor)) in entries.into_iter().enumerate() {
+        let slot = if idx == len - 1 { &slots[last] } else { &slots[idx] };
+        let pick = |own: &str, slot: &str, fallback: &dyn Fn(&str) -> String| {
+            if has_comment(own) {
+                own.to_owned()
+            } else if has_comment(slot) {
+                fallback(slot)
+            } else {
+                slot.to_owned()
+            }
+        };
+        let after_last_line = |s: &str| s.rsplit('\n').next().unwrap_or("").to_owned();
+        let leading = pick(&decor.leading, &slot.leading, &after_last_line);
+        let suffix = pick(&decor.suffix, &slot.suffix, &|_| String::new());
+        let end = pick(&decor.end, &slot.end, &|_| "\n".to_owned());
+
+        value.decor_mut().set_prefix(format!("{prev_end}{leading}"));
+        if idx == len - 1 && !trailing_comma {
+            value.decor_mut().set_suffix(format!("{suffix}{end}{closing}"));
+        } else {
+            value.decor_mut().set_suffix(suffix);
+        }
+        arr.push_formatted(value);
+        prev_end = end;
+    }
+    if trailing_comma {
+        arr.set_trailing(format!("{prev_end}{closing}"));
+    }
 }
 
-fn sort_table(table: &mut Table, group: bool) {
-    if group {
-        sort_by_group(table);
+/// The elements of `arr` that are equal to an element before them.
+fn array_duplicates(arr: &Array) -> Vec<String> {
+    let mut seen: Vec<&Value> = vec![];
+    let mut duplicates = vec![];
+    for value in arr.iter() {
+        if seen.iter().any(|v| cmp_values(v, value) == Ordering::Equal) {
+            duplicates.push(value_repr(value));
+        } else {
+            seen.push(value);
+        }
+    }
+    duplicates
+}
+
+/// Whether the dotted `pattern` names `path`.
+///
+/// A `*` segment matches any one key, and a segment may contain dots itself so
+/// `package.metadata.docs.rs.features` names the `features` key of
+/// `[package.metadata."docs.rs"]`.
+pub(crate) fn dotted_path_matches(pattern: &str, path: &[String]) -> bool {
+    let Some((seg, rest)) = path.split_first() else {
+        return pattern.is_empty();
+    };
+    let quoted = format!("\"{seg}\"");
+    let Some(remainder) = pattern
+        .strip_prefix('*')
+        .or_else(|| pattern.strip_prefix(quoted.as_str()))
+        .or_else(|| pattern.strip_prefix(seg.as_str()))
+    else {
+        return false;
+    };
+    if rest.is_empty() {
+        remainder.is_empty()
+    } else {
+        remainder.strip_prefix('.').is_some_and(|r| dotted_path_matches(r, rest))
+    }
+}
+
+/// Sorts the arrays whose dotted path matches one of `patterns`.
+fn sort_arrays_at_paths(
+    table: &mut dyn TableLike,
+    path: &mut Vec<String>,
+    patterns: &[String],
+    dedupe: bool,
+) {
+    for (key, item) in table.iter_mut() {
+        path.push(key.get().to_owned());
+        match item {
+            Item::Value(Value::Array(arr))
+                if patterns.iter().any(|pat| dotted_path_matches(pat, path)) =>
+            {
+                sort_array(arr, dedupe);
+            }
+            Item::Value(Value::InlineTable(inner)) => {
+                sort_arrays_at_paths(inner, path, patterns, dedupe);
+            }
+            Item::Table(inner) if !Directive::Skip.on_table(inner) => {
+                sort_arrays_at_paths(inner, path, patterns, dedupe);
+            }
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+/// Calls `f` with the path of every array matching one of `patterns`.
+fn visit_arrays_at_paths(
+    table: &dyn TableLike,
+    path: &mut Vec<String>,
+    patterns: &[String],
+    f: &mut dyn FnMut(&[String], &Array),
+) {
+    for (key, item) in table.iter() {
+        path.push(key.to_owned());
+        match item {
+            Item::Value(Value::Array(arr))
+                if patterns.iter().any(|pat| dotted_path_matches(pat, path)) =>
+            {
+                f(path, arr);
+            }
+            Item::Value(Value::InlineTable(inner)) => {
+                visit_arrays_at_paths(inner, path, patterns, f);
+            }
+            Item::Table(inner) if !Directive::Skip.on_table(inner) => {
+                visit_arrays_at_paths(inner, path, patterns, f);
+            }
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+/// The arrays `sort_toml` sorts, `matcher.heading_key` and the `sort_arrays`
+/// patterns.
+pub(crate) fn sorted_array_patterns(
+    matcher: &Matcher<'_>,
+    config: &Config,
+) -> Vec<String> {
+    matcher
+        .heading_key
+        .iter()
+        .map(|(heading, key)| format!("{heading}.{key}"))
+        .chain(config.sort_arrays.iter().cloned())
+        .collect()
+}
+
+/// Finds duplicate entries in the arrays `sort_toml` sorts.
+pub(crate) fn duplicate_array_entries(
+    toml: &DocumentMut,
+    matcher: &Matcher<'_>,
+    config: &Config,
+) -> Vec<String> {
+    let patterns = sorted_array_patterns(matcher, config);
+    let mut found = vec![];
+    visit_arrays_at_paths(toml.as_table(), &mut vec![], &patterns, &mut |path, arr| {
+        for duplicate in array_duplicates(arr) {
+            let path = path.join(".");
+            found.push(format!("`{path}` contains {duplicate} more than once"));
+        }
+    });
+    found
+}
+
+/// The dotted paths of the arrays in `sort_arrays` that are not sorted.
+pub(crate) fn unsorted_arrays(toml: &DocumentMut, config: &Config) -> Vec<String> {
+    let mut found = vec![];
+    visit_arrays_at_paths(
+        toml.as_table(),
+        &mut vec![],
+        &config.sort_arrays,
+        &mut |path, arr| {
+            if !arr.iter().is_sorted_by(|a, b| cmp_values(a, b) != Ordering::Greater) {
+                found.push(path.join("."));
+            }
+        },
+    );
+    found
+}
+
+fn sort_table(table: &mut Table, group: Option<GroupBy>) {
+    sort_around_off_ranges(table, |table| {
+        if let Some(group_by) = group {
+            sort_by_group(table, group_by);
+        } else {
+            table.sort_values_by(|k1, v1, k2, v2| {
+                is_pinned(k2, v2).cmp(&is_pinned(k1, v1)).then_with(|| k1.cmp(k2))
+            });
+        }
+    });
+}
+
+/// Sorts a dependency table, split into groups by source if `group_by_source` is
+/// set.
+fn sort_dependencies(table: &mut Table, group: Option<GroupBy>, config: &Config) {
+    if config.group_by_source {
+        sort_around_off_ranges(table, |table| {
+            sort_by_source(table, config.source_group_headers);
+        });
     } else {
-        table.sort_values();
+        sort_table(table, group);
+    }
+}
+
+/// Sorts `table` with `sort`, unless it has a `# cargo-sort: skip` directive.
+///
+/// The key value pairs between a `# cargo-sort: off` and `# cargo-sort: on` are
+/// kept where they are, the pairs before and after such a range are sorted on
+/// their own.
+fn sort_around_off_ranges(table: &mut Table, sort: impl Fn(&mut Table)) {
+    if Directive::Skip.on_table(table) {
+        return;
+    }
+    if !table.iter().any(|(k, _)| {
+        table
+            .get_key_value(k)
+            .is_some_and(|(k, v)| Directive::Off.in_prefix(&entry_prefix(k, v)))
+    }) {
+        sort(table);
+        return;
+    }
+
+    let table_clone = table.clone();
+    table.clear();
+
+    let mut range = Table::new();
+    // The `# cargo-sort: on` comment that started `range`, it stays at the top
+    let mut range_header = String::new();
+    let mut off = false;
+    for (k, _) in table_clone.iter() {
+        let (k, v) = table_clone.get_key_value(k).unwrap();
+        let prefix = entry_prefix(k, v);
+        if off && Directive::On.in_prefix(&prefix) {
+            off = false;
+            let (mut k, mut v) = (k.clone(), v.clone());
+            let (header, rest): (Vec<_>, Vec<_>) =
+                prefix.split_inclusive('\n').partition(|l| Directive::parse(l).is_some());
+            range_header = header.concat();
+            set_entry_prefix(&mut k, &mut v, rest.concat());
+            range.insert_formatted(&k, v);
+            continue;
+        }
+        if !off && Directive::Off.in_prefix(&prefix) {
+            sort_range(table, &mut range, &mut range_header, &sort);
+            off = true;
+        }
+        if off {
+            table.insert_formatted(k, v.clone());
+        } else {
+            range.insert_formatted(k, v.clone());
+        }
     }
+    sort_range(table, &mut range, &mut range_header, &sort);
 }
 
-fn sort_nested_table(table: &mut Table, target_tables: &TargetTablePaths) {
+/// Sorts the key value pairs in `range` and appends them to `table`.
+fn sort_range(
+    table: &mut Table,
+    range: &mut Table,
+    range_header: &mut String,
+    sort: &impl Fn(&mut Table),
+) {
+    sort(range);
+    for (idx, (k, _)) in range.iter().enumerate() {
+        let (k, v) = range.get_key_value(k).unwrap();
+        let (mut k, mut v) = (k.clone(), v.clone());
+        if idx == 0 {
+            let prefix = format!("{range_header}{}", entry_prefix(&k, &v));
+            set_entry_prefix(&mut k, &mut v, prefix);
+        }
+        table.insert_formatted(&k, v);
+    }
+    *range = Table::new();
+    range_header.clear();
+}
+
+/// Whether the key has a `# cargo-sort: pin-top` directive.
+fn is_pinned(key: &Key, item: &Item) -> bool {
+    Directive::PinTop.in_prefix(&entry_prefix(key, item))
+}
+
+fn sort_nested_table(
+    table: &mut Table,
+    target_tables: &TargetTablePaths,
+    group: Option<GroupBy>,
+    config: &Config,
+) {
     // The `table` name must be `target`
     for paths in target_tables.values() {
         for path in paths {
             if path.len() > 1 {
-                sort_table_by_path(table, &path[1..]);
+                sort_table_by_path(table, &path[1..], group, config);
             }
         }
     }
 }
 
-fn sort_table_by_path(table: &mut Table, path: &[String]) {
+fn sort_table_by_path(
+    table: &mut Table,
+    path: &[String],
+    group: Option<GroupBy>,
+    config: &Config,
+) {
     let Some(first) = path.first() else {
-        table.sort_values();
+        sort_dependencies(table, group, config);
         return;
     };
     if let Some(Item::Table(inner_table)) = table.get_mut(first) {
-        sort_table_by_path(inner_table, &path[1..]);
+        sort_table_by_path(inner_table, &path[1..], group, config);
+    }
+}
+
+/// Marks the heading being collected as complete, it has key value pairs or is an
+/// empty table.
+fn complete_heading(keys: &mut Vec<Heading>) {
+    if let Some(Heading::Next(segs)) = keys.last_mut() {
+        let segs = std::mem::take(segs);
+        keys.pop();
+        keys.push(Heading::Complete(segs));
     }
 }
 
 fn gather_headings(table: &Table, keys: &mut Vec<Heading>, depth: usize) {
     if table.is_empty() && !table.is_implicit() {
-        let next = match keys.pop().unwrap() {
-            Heading::Next(segs) => Heading::Complete(segs),
-            comp => comp,
-        };
-        keys.push(next);
+        complete_heading(keys);
     }
     for (head, item) in table.iter() {
         match item {
-            Item::Value(_) => {
-                if keys.last().is_some_and(|h| matches!(h, Heading::Complete(_))) {
-                    continue;
-                }
-                let next = match keys.pop().unwrap() {
-                    Heading::Next(segs) => Heading::Complete(segs),
-                    _complete => unreachable!("the above if check prevents this"),
-                };
-                keys.push(next);
-                continue;
-            }
+            Item::Value(_) => complete_heading(keys),
             Item::Table(table) => {
-                let next = match keys.pop().unwrap() {
-                    Heading::Next(mut segs) => {
+                let next = match keys.pop() {
+                    Some(Heading::Next(mut segs)) => {
                         segs.push(head.into());
                         Heading::Next(segs)
                     }
@@ -245,78 +883,176 @@ fn gather_headings(table: &Table, keys: &mut Vec<Heading>, depth: usize) {
                     //
                     // [heading]       // transitioning from here to
                     // [heading.segs]  // here
-                    Heading::Complete(segs) => {
-                        let take = depth.max(1);
+                    Some(Heading::Complete(segs)) => {
+                        let take = depth.max(1).min(segs.len());
                         let mut next = segs[..take].to_vec();
                         next.push(head.into());
                         keys.push(Heading::Complete(segs));
                         Heading::Next(next)
                     }
+                    None => Heading::Next(vec![head.into()]),
                 };
                 keys.push(next);
                 gather_headings(table, keys, depth + 1);
             }
-            Item::ArrayOfTables(_arr) => unreachable!("no [[heading]] are sorted"),
-            Item::None => unreachable!("an empty table will not be sorted"),
+            // `[[heading.segs]]` are placed after the table they are nested in, the
+            // heading being collected stays on top
+            Item::ArrayOfTables(_) => {
+                let mut segs = match keys.last() {
+                    Some(Heading::Next(segs)) => segs.clone(),
+                    Some(Heading::Complete(segs)) => {
+                        segs[..depth.min(segs.len())].to_vec()
+                    }
+                    None => vec![],
+                };
+                segs.push(head.into());
+                keys.insert(keys.len().saturating_sub(1), Heading::Complete(segs));
+            }
+            Item::None => {}
         }
     }
 }
 
-fn sort_by_group(table: &mut Table) {
+fn sort_by_group(table: &mut Table, group_by: GroupBy) {
     let table_clone = table.clone();
     table.clear();
 
-    let mut groups = BTreeMap::new();
-    let mut group_decor = BTreeMap::default();
+    let mut groups: BTreeMap<usize, Vec<(Key, Item)>> = BTreeMap::new();
+    let mut group_prefix = BTreeMap::new();
 
+    let mut header_tables = vec![];
     let mut curr = 0;
     for (idx, (k, _)) in table_clone.iter().enumerate() {
         let (k, v) = table_clone.get_key_value(k).unwrap();
+        // The decor of `[dependencies.foo]` tables is part of their heading, a group
+        // comment on the key would end up inside the brackets
+        if v.as_table().is_some_and(|t| !t.is_dotted()) || v.is_array_of_tables() {
+            header_tables.push((k, v));
+            continue;
+        }
 
-        // If the item is a dotted table, grab the decor of the first item of the table
-        // instead.
-        let decor = if let Some(first_in_dotted) =
-            v.as_table().filter(|t| t.is_dotted()).and_then(|t| t.key(t.iter().next()?.0))
-        {
-            first_in_dotted.leaf_decor()
-        } else {
-            k.leaf_decor()
-        };
-
-        let blank_lines = decor
-            .prefix()
-            .and_then(RawString::as_str)
-            .unwrap_or("")
-            .lines()
-            .filter(|l| !l.starts_with('#'))
-            .count();
-
-        if blank_lines > 0 {
-            let decor = k.leaf_decor().clone();
-            let k = k.clone().with_leaf_decor(Decor::default());
+        let prefix = entry_prefix(k, v);
+        if group_by.starts_group(&prefix) {
+            // A `# cargo-sort: pin-top` stays with its key, the rest of the prefix
+            // stays at the top of the group
+            let (pin, rest): (Vec<_>, Vec<_>) = prefix
+                .split_inclusive('\n')
+                .partition(|l| Directive::parse(l) == Some(Directive::PinTop));
+            let (mut k, mut v) = (k.clone(), v.clone());
+            set_entry_prefix(&mut k, &mut v, pin.concat());
 
             groups.entry(idx).or_insert_with(|| vec![(k, v)]);
-            group_decor.insert(idx, decor);
+            group_prefix.insert(idx, rest.concat());
             curr = idx;
         } else {
-            groups.entry(curr).or_default().push((k.clone(), v));
+            groups.entry(curr).or_default().push((k.clone(), v.clone()));
         }
     }
 
     for (idx, mut group) in groups {
-        group.sort_by(|a, b| a.0.cmp(&b.0));
-        let group_decor = group_decor.remove(&idx);
+        group.sort_by(|a, b| {
+            is_pinned(&b.0, &b.1).cmp(&is_pinned(&a.0, &a.1)).then(a.0.cmp(&b.0))
+        });
+        let group_prefix = group_prefix.remove(&idx);
 
-        for (idx, (mut k, v)) in group.into_iter().enumerate() {
+        for (idx, (mut k, mut v)) in group.into_iter().enumerate() {
             if idx == 0 {
-                if let Some(group_decor) = group_decor.clone() {
-                    k = k.with_leaf_decor(group_decor);
+                if let Some(group_prefix) = &group_prefix {
+                    // Keep the comments of a key that was sorted to the top of the group
+                    // below the group's own prefix
+                    let prefix = format!("{group_prefix}{}", entry_prefix(&k, &v));
+                    set_entry_prefix(&mut k, &mut v, prefix);
+                }
+            }
+
+            table.insert_formatted(&k, v);
+        }
+    }
+    for (k, v) in header_tables {
+        table.insert_formatted(k, v.clone());
+    }
+}
+
+/// Splits a dependency table into groups by `DependencySource`, each group is
+/// sorted and separated from the previous one by a blank line.
+///
+/// The groups are derived again on every run, so blank lines and generated headers
+/// from a previous run are removed before the entries are regrouped.
+fn sort_by_source(table: &mut Table, headers: bool) {
+    let table_clone = table.clone();
+    table.clear();
+
+    let mut groups: BTreeMap<DependencySource, Vec<(Key, Item)>> = BTreeMap::new();
+    let mut header_tables = vec![];
+    for (k, _) in table_clone.iter() {
+        let (k, v) = table_clone.get_key_value(k).unwrap();
+        // `[dependencies.foo]` tables are written as their own headings, there is
+        // nothing to group
+        if v.as_table().is_some_and(|t| !t.is_dotted()) || v.is_array_of_tables() {
+            header_tables.push((k.clone(), v.clone()));
+            continue;
+        }
+
+        let (mut k, mut v) = (k.clone(), v.clone());
+        let prefix = entry_prefix(&k, &v)
+            .lines()
+            .filter(|l| {
+                let l = l.trim();
+                !l.is_empty() && DependencySource::ALL.iter().all(|s| s.header() != l)
+            })
+            .map(|l| format!("{}\n", l.trim_start()))
+            .collect::<String>();
+        set_entry_prefix(&mut k, &mut v, prefix);
+        groups.entry(DependencySource::of(&v)).or_default().push((k, v));
+    }
+
+    for (idx, (source, mut group)) in groups.into_iter().enumerate() {
+        group.sort_by(|a, b| {
+            is_pinned(&b.0, &b.1).cmp(&is_pinned(&a.0, &a.1)).then(a.0.cmp(&b.0))
+        });
+        for (i, (mut k, mut v)) in group.into_iter().enumerate() {
+            if i == 0 {
+                let mut prefix = if idx == 0 { String::new() } else { "\n".to_owned() };
+                if headers {
+                    prefix.push_str(source.header());
+                    prefix.push('\n');
                 }
+                prefix.push_str(&entry_prefix(&k, &v));
+                set_entry_prefix(&mut k, &mut v, prefix);
             }
+            table.insert_formatted(&k, v);
+        }
+    }
+
+    for (k, v) in header_tables {
+        table.insert_formatted(&k, v);
+    }
+}
+
+/// The decor prefix of a key value pair, for dotted keys (`foo.workspace = true`)
+/// this is the prefix of the first key in the dotted table.
+pub(crate) fn entry_prefix(key: &Key, item: &Item) -> String {
+    let decor = if let Some(first_in_dotted) =
+        item.as_table().filter(|t| t.is_dotted()).and_then(|t| t.key(t.iter().next()?.0))
+    {
+        first_in_dotted.leaf_decor()
+    } else {
+        key.leaf_decor()
+    };
+    decor.prefix().and_then(RawString::as_str).unwrap_or("").to_owned()
+}
 
-            table.insert_formatted(&k, v.clone());
+fn set_entry_prefix(key: &mut Key, item: &mut Item, prefix: String) {
+    if let Some(dotted) = item.as_table_mut().filter(|t| t.is_dotted()) {
+        let first = dotted.iter().next().map(|(k, _)| k.to_owned());
+        if let Some(first) = first {
+            if let Some(mut first) = dotted.key_mut(&first) {
+                first.leaf_decor_mut().set_prefix(prefix);
+            }
+            return;
         }
     }
+    key.leaf_decor_mut().set_prefix(prefix);
 }
 
 fn sort_lexicographical(
@@ -326,146 +1062,473 @@ fn sort_lexicographical(
 ) {
     // Since the root table is always index 0 we add one
     let first_table_idx = first_table.unwrap_or_default() + 1;
-    for (idx, heading) in heading_order.iter().flat_map(|(_, segs)| segs).enumerate() {
-        if let Heading::Complete(segs) = heading {
-            let mut nested = 0;
-            let mut table = Some(toml.as_table_mut());
-            for seg in segs {
-                nested += 1;
-                table = table.and_then(|t| t[seg].as_table_mut());
-            }
-            // Do not reorder the unsegmented tables
-            if nested > 1 {
-                if let Some(table) = table {
-                    table.set_position(Some((first_table_idx + idx) as isize));
+    for (idx, heading) in heading_order.values().flatten().enumerate() {
+        // Do not reorder the unsegmented tables
+        let Heading::Complete(segs) = heading else { continue };
+        if segs.len() < 2 {
+            continue;
+        }
+        let position = Some((first_table_idx + idx) as isize);
+        match item_at_path(toml.as_table_mut(), segs) {
+            Some(Item::Table(table)) => table.set_position(position),
+            Some(Item::ArrayOfTables(arr)) => {
+                for table in arr.iter_mut() {
+                    table.set_position(position);
                 }
             }
+            _ => {}
+        }
+    }
+}
+
+/// A table, or a whole array of tables, that is given a position in the document
+/// by `sort_by_ordering`.
+#[derive(Debug)]
+struct TableUnit {
+    /// The segments of the heading, `[target.'cfg(unix)'.dependencies]` is
+    /// `["target", "cfg(unix)", "dependencies"]`.
+    path: Vec<String>,
+    /// Whether this is an array of tables `[[heading]]`.
+    array: bool,
+    /// The position of the table in the input document.
+    position: Option<isize>,
+}
+
+impl TableUnit {
+    /// The dependency kind and cfg of a `[target.<cfg>.<kind>]` table, or `None`
+    /// for any other table.
+    fn target_kind<'a>(&'a self, dep_headings: &[&str]) -> Option<(&'a str, &'a str)> {
+        match self.path.as_slice() {
+            [target, cfg, kind, ..]
+                if target == TARGET && dep_headings.contains(&kind.as_str()) =>
+            {
+                Some((kind, cfg))
+            }
+            _ => None,
         }
     }
+
+    /// The top level table this unit is kept together with, the dependency kind
+    /// for target tables.
+    fn base<'a>(&'a self, dep_headings: &[&str]) -> &'a str {
+        canonical_key(
+            self.target_kind(dep_headings).map_or(&self.path[0], |(kind, _)| kind),
+        )
+    }
 }
 
+fn collect_table_units(
+    table: &Table,
+    path: &mut Vec<String>,
+    units: &mut Vec<TableUnit>,
+) {
+    for (key, item) in table.iter() {
+        path.push(key.to_owned());
+        match item {
+            Item::Table(inner) if !inner.is_dotted() => {
+                // Implicit tables like `[profile]` go where their first table is
+                let position = if inner.is_implicit() {
+                    fmt::first_table_position(inner)
+                } else {
+                    inner.position()
+                };
+                units.push(TableUnit { path: path.clone(), array: false, position });
+                collect_table_units(inner, path, units);
+            }
+            Item::ArrayOfTables(arr) => units.push(TableUnit {
+                path: path.clone(),
+                array: true,
+                position: arr.get(0).and_then(Table::position),
+            }),
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+/// How specific the `table_order` entry `pattern` is for the table at `path`, or
+/// `None` if it does not match.
+///
+/// A pattern matches the table it names and every table nested under it, a `*`
+/// segment matches any single segment. A plain dependency heading such as
+/// `dependencies` also matches the `[target.<cfg>.dependencies]` tables, but less
+/// specifically than any pattern that names them.
+fn pattern_specificity(
+    pattern: &str,
+    path: &[String],
+    dep_headings: &[&str],
+) -> Option<(usize, usize)> {
+    let segs = pattern.split('.').collect::<Vec<_>>();
+    let matches = |path: &[String]| {
+        segs.len() <= path.len()
+            && segs
+                .iter()
+                .zip(path)
+                .all(|(pat, seg)| *pat == "*" || canonical_key(pat) == canonical_key(seg))
+    };
+
+    if matches(path) {
+        return Some((segs.len(), segs.iter().filter(|&&pat| pat != "*").count()));
+    }
+    match path {
+        [target, _cfg, rest @ ..]
+            if target == TARGET
+                && segs.len() == 1
+                && dep_headings.contains(&segs[0])
+                && matches(rest) =>
+        {
+            Some((0, 0))
+        }
+        _ => None,
+    }
+}
+
+/// Gives every table a position according to `ordering`.
+///
+/// Each table goes to the most specific entry of `ordering` that matches it, the
+/// tables no entry matches go to the `"..."` entry or after all the others. Within
+/// an entry, dependency tables are sorted with target tables after the others
+/// and all other tables keep their order from the input.
+///
+/// With `target_after_base` a `[target.<cfg>.<kind>]` table always goes right
+/// after the `[<kind>]` table, whichever entry matches it.
 fn sort_by_ordering(
     ordering: &[String],
-    heading_order: &BTreeMap<(usize, String), Vec<Heading>>,
+    dep_headings: &[&str],
+    target_after_base: bool,
     toml: &mut DocumentMut,
 ) {
-    let mut idx = 0;
-    for heading in ordering {
-        let mut matches: Vec<(&(usize, String), &Vec<Heading>)> = heading_order
+    let mut units = vec![];
+    collect_table_units(toml.as_table(), &mut vec![], &mut units);
+    units.sort_by_key(|unit| unit.position);
+
+    let unlisted = ordering.iter().position(|o| o == UNLISTED).unwrap_or(ordering.len());
+    let slot_of = |path: &[String]| {
+        ordering
             .iter()
-            .filter(|((_s, key), headings)| {
-                key == heading
-                    || headings.iter().any(|h| {
-                        if let Heading::Complete(segs) = h {
-                            segs.iter().any(|seg| seg == heading)
-                        } else {
-                            false
-                        }
-                    })
+            .enumerate()
+            .filter_map(|(idx, pattern)| {
+                Some((pattern_specificity(pattern, path, dep_headings)?, idx))
             })
-            .collect();
-
-        /// Use `heading` as the split point, and divide `segs` into two parts:
-        /// - Traverse left (backward) to the start (including `heading`)
-        /// - Traverse right (forward) to the end (after `heading`)
-        ///
-        /// Then join both parts into a dot-separated string, for example:
-        /// `[target.'cfg(windows)'.dependencies.windows-sys]` will be
-        /// `[dependencies.'cfg(windows)'.target.windows-sys]`
-        fn join_segs_around_heading(segs: &[String], heading: &str) -> Option<String> {
-            if let Some(pos) = segs.iter().position(|seg| seg == heading) {
-                let mut left: Vec<_> = segs[..=pos].iter().rev().cloned().collect();
-                let right: Vec<_> = if pos + 1 < segs.len() {
-                    segs[pos + 1..].to_vec()
-                } else {
-                    Vec::new()
+            // The first entry wins when two are just as specific
+            .max_by(|(a, a_idx), (b, b_idx)| a.cmp(b).then(b_idx.cmp(a_idx)))
+            .map_or(unlisted, |(_, idx)| idx)
+    };
+    let mut slots: Vec<Vec<&TableUnit>> = vec![vec![]; ordering.len() + 1];
+    for unit in &units {
+        let base_slot = unit
+            .target_kind(dep_headings)
+            .filter(|_| target_after_base)
+            .and_then(|(kind, _)| units.iter().find(|u| u.path == [kind]))
+            .map(|base| slot_of(&base.path));
+        slots[base_slot.unwrap_or_else(|| slot_of(&unit.path))].push(unit);
+    }
+
+    let mut idx = 1;
+    for mut slot in slots {
+        // Keep each top level table together, in the order they first appear
+        let mut bases: Vec<&str> = vec![];
+        for unit in &slot {
+            let base = unit.base(dep_headings);
+            if !bases.contains(&base) {
+                bases.push(base);
+            }
+        }
+        slot.sort_by(|a, b| {
+            let (a_base, b_base) = (a.base(dep_headings), b.base(dep_headings));
+            let base_idx = |base| bases.iter().position(|&b| b == base);
+            base_idx(a_base).cmp(&base_idx(b_base)).then_with(|| {
+                if a_base == PROFILE && b_base == PROFILE {
+                    return profile_sort_key(&a.path).cmp(&profile_sort_key(&b.path));
+                }
+                if !dep_headings.contains(&a_base) || a_base != b_base {
+                    return Ordering::Equal;
+                }
+                let key = |unit: &'_ TableUnit| match unit.target_kind(dep_headings) {
+                    Some((_, cfg)) => {
+                        (true, normalize_cfg(cfg), unit.path[2..].join("."))
+                    }
+                    None => (false, String::new(), unit.path.join(".")),
                 };
-                left.extend(right);
-                return Some(left.join("."));
+                key(a).cmp(&key(b))
+            })
+        });
+
+        for unit in slot {
+            let Some(item) = item_at_path(toml.as_table_mut(), &unit.path) else {
+                continue;
+            };
+            if unit.array {
+                for tab in
+                    item.as_array_of_tables_mut().into_iter().flat_map(|a| a.iter_mut())
+                {
+                    tab.set_position(Some(idx));
+                    idx += 1;
+                    walk_tables_set_position(tab, &mut idx);
+                }
+            } else if let Some(tab) = item.as_table_mut() {
+                tab.set_position(Some(idx));
+                idx += 1;
             }
-            None
         }
+    }
+}
 
-        fn extract_heading_segments(headings: &[Heading], heading: &str) -> String {
-            headings
+/// The key `[profile.*]` tables are sorted by, the built-in profiles come first
+/// then the custom profiles alphabetically, each followed by its `package.*` and
+/// `build-override` tables.
+fn profile_sort_key(path: &[String]) -> Option<(usize, String, String)> {
+    match path {
+        [profile, name, rest @ ..] if profile == PROFILE => {
+            let rank = BUILTIN_PROFILES
                 .iter()
-                .filter_map(|h| {
-                    if let Heading::Complete(segs) = h {
-                        return join_segs_around_heading(segs, heading);
-                    }
-                    None
-                })
-                .max_by_key(|s| s.len())
-                .unwrap_or_default()
-        }
-
-        matches.sort_by(|((_, a_key), a_headings), ((_, b_key), b_headings)| {
-            let a1_longest = extract_heading_segments(a_headings, heading);
-            let b1_longest = extract_heading_segments(b_headings, heading);
-            let ord = a1_longest.cmp(&b1_longest);
-            if ord == Ordering::Equal {
-                a_key.cmp(b_key)
-            } else {
-                ord
-            }
+                .position(|p| p == name)
+                .unwrap_or(BUILTIN_PROFILES.len());
+            Some((rank, name.to_owned(), rest.join(".")))
+        }
+        _ => None,
+    }
+}
+
+/// Sorts the tables nested under `path` by `key`, among the positions they already
+/// have.
+fn sort_among_positions<K: Ord>(
+    toml: &mut DocumentMut,
+    path: &[&str],
+    key: impl Fn(&[String]) -> K,
+) {
+    let Some(table) = item_at_path(toml.as_table_mut(), path).and_then(|t| t.as_table())
+    else {
+        return;
+    };
+    let mut units = vec![];
+    let mut path = path.iter().map(|&s| s.to_owned()).collect();
+    collect_table_units(table, &mut path, &mut units);
+    // Implicit tables like `[profile.release.package]` are not written out, their
+    // position must not be handed to one that is
+    units.retain(|unit| {
+        !unit.array
+            && item_at_path(toml.as_table_mut(), &unit.path)
+                .and_then(Item::as_table_mut)
+                .is_some_and(|t| !t.is_implicit())
+    });
+
+    let mut positions = units.iter().map(|unit| unit.position).collect::<Vec<_>>();
+    positions.sort();
+    units.sort_by_key(|unit| key(&unit.path));
+    for (unit, position) in units.iter().zip(positions) {
+        if let Some(table) =
+            item_at_path(toml.as_table_mut(), &unit.path).and_then(Item::as_table_mut)
+        {
+            table.set_position(position);
+        }
+    }
+}
+
+/// Sorts a `[lints.<tool>]` table, the lints with a `priority` come first ordered
+/// by priority, then all other lints alphabetically.
+fn sort_lint_table(table: &mut Table) {
+    sort_around_off_ranges(table, |table| {
+        table.sort_values_by(|k1, v1, k2, v2| {
+            let key = |k: &Key, v: &Item| {
+                let priority = v.get("priority").and_then(Item::as_integer);
+                (priority.is_none(), priority, k.get().to_owned())
+            };
+            is_pinned(k2, v2).cmp(&is_pinned(k1, v1)).then_with(|| {
+                if is_pinned(k1, v1) {
+                    Ordering::Equal
+                } else {
+                    key(k1, v1).cmp(&key(k2, v2))
+                }
+            })
         });
+    });
+}
 
-        if !matches.is_empty() {
-            for &((_, key), to_sort_headings) in &matches {
-                let mut to_sort_headings = to_sort_headings
-                    .iter()
-                    .filter(|h| {
-                        if let Heading::Complete(segs) = h {
-                            if key == TARGET {
-                                // Get rid of the items that do not contain the heading
-                                return segs.iter().any(|seg| seg == heading);
-                            } else {
-                                return true;
-                            }
-                        }
-                        false
-                    })
-                    .collect::<Vec<_>>();
-                to_sort_headings.sort_by_key(|h| {
-                    if let Heading::Complete(segs) = h {
-                        if key == TARGET {
-                            join_segs_around_heading(segs, heading).unwrap_or_default()
-                        } else {
-                            segs.to_vec().join(".")
-                        }
-                    } else {
-                        String::new()
-                    }
-                });
-                for h in to_sort_headings {
-                    if let Heading::Complete(segs) = h {
-                        let mut table = Some(toml.as_table_mut());
-                        for seg in segs {
-                            table = table.and_then(|t| t[seg].as_table_mut());
-                        }
-                        // Do not reorder the unsegmented tables
-                        if let Some(table) = table {
-                            table.set_position(Some(idx));
-                            idx += 1;
-                        }
+/// Finds the keys spelled with a deprecated underscore, and renames them to their
+/// hyphenated spelling if `fix` is set.
+///
+/// Returns a message for every deprecated key found. When both spellings are
+/// present the entries of the underscore spelling are merged into the hyphenated
+/// one, on conflicts the hyphenated entry is kept as that is the one cargo uses.
+pub(crate) fn deprecated_keys(toml: &mut DocumentMut, fix: bool) -> Vec<String> {
+    const DEP_TABLES: &[&str] = &["dev_dependencies", "build_dependencies"];
+
+    let mut found = vec![];
+    let root = toml.as_table_mut();
+    fix_deprecated_in_table(root, &[], DEP_TABLES, fix, &mut found);
+
+    let mut dep_tables = vec![];
+    for &kind in MATCHER.heading {
+        dep_tables.push(vec![kind.to_owned()]);
+    }
+    dep_tables.push(vec!["workspace".to_owned(), "dependencies".to_owned()]);
+    if let Some(target) = root.get_mut(TARGET).and_then(Item::as_table_mut) {
+        let cfgs = target.iter().map(|(cfg, _)| cfg.to_owned()).collect::<Vec<_>>();
+        for cfg in cfgs {
+            let path = [TARGET.to_owned(), cfg.clone()];
+            if let Some(cfg_table) = target.get_mut(&cfg).and_then(Item::as_table_mut) {
+                fix_deprecated_in_table(cfg_table, &path, DEP_TABLES, fix, &mut found);
+            }
+            for &kind in MATCHER.heading {
+                dep_tables.push(vec![TARGET.to_owned(), cfg.clone(), kind.to_owned()]);
+            }
+        }
+    }
+
+    for path in dep_tables {
+        let Some(deps) = item_at_path(root, &path).and_then(Item::as_table_mut) else {
+            continue;
+        };
+        let names = deps.iter().map(|(name, _)| name.to_owned()).collect::<Vec<_>>();
+        for name in names {
+            let mut path = path.clone();
+            path.push(name.clone());
+            match deps.get_mut(&name) {
+                Some(Item::Table(dep)) => fix_deprecated_in_table(
+                    dep,
+                    &path,
+                    &["default_features"],
+                    fix,
+                    &mut found,
+                ),
+                Some(Item::Value(Value::InlineTable(dep))) => {
+                    fix_deprecated_in_inline_table(dep, &path, fix, &mut found);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(lib) = root.get_mut("lib").and_then(Item::as_table_mut) {
+        fix_deprecated_in_table(
+            lib,
+            &["lib".to_owned()],
+            &["crate_type"],
+            fix,
+            &mut found,
+        );
+    }
+    if let Some(examples) = root.get_mut("example").and_then(Item::as_array_of_tables_mut)
+    {
+        for example in examples.iter_mut() {
+            let path = ["example".to_owned()];
+            fix_deprecated_in_table(example, &path, &["crate_type"], fix, &mut found);
+        }
+    }
+
+    found
+}
+
+fn deprecated_key_message(path: &[String], from: &str, to: &str) -> String {
+    let path =
+        path.iter().map(String::as_str).chain([from]).collect::<Vec<_>>().join(".");
+    format!("`{path}` is deprecated, use `{to}` instead")
+}
+
+fn fix_deprecated_in_table(
+    table: &mut Table,
+    path: &[String],
+    keys: &[&str],
+    fix: bool,
+    found: &mut Vec<String>,
+) {
+    for &(from, to) in DEPRECATED_KEYS.iter().filter(|(from, _)| keys.contains(from)) {
+        if !table.contains_key(from) {
+            continue;
+        }
+        found.push(deprecated_key_message(path, from, to));
+        if !fix {
+            continue;
+        }
+
+        if let Some(existing) = table.get(to) {
+            let mut merged = existing.clone();
+            if let (Some(merged), Some(deprecated)) =
+                (merged.as_table_mut(), table.get(from).and_then(Item::as_table))
+            {
+                for (k, _) in deprecated.iter() {
+                    let (k, v) = deprecated.get_key_value(k).unwrap();
+                    if !merged.contains_key(k.get()) {
+                        merged.insert_formatted(k, v.clone());
                     }
                 }
             }
-        } else if let Some(tab) = toml.as_table_mut()[heading].as_table_mut() {
-            tab.set_position(Some(idx));
-            idx += 1;
-            walk_tables_set_position(tab, &mut idx);
-        } else if let Some(arrtab) = toml.as_table_mut()[heading].as_array_of_tables_mut()
-        {
-            for tab in arrtab.iter_mut() {
-                tab.set_position(Some(idx));
-                idx += 1;
-                walk_tables_set_position(tab, &mut idx);
+            table.insert(to, merged);
+            table.remove(from);
+        } else {
+            let table_clone = table.clone();
+            table.clear();
+            for (k, _) in table_clone.iter() {
+                let (k, v) = table_clone.get_key_value(k).unwrap();
+                if k.get() == from {
+                    let renamed = Key::new(to).with_leaf_decor(k.leaf_decor().clone());
+                    table.insert_formatted(&renamed, v.clone());
+                } else {
+                    table.insert_formatted(k, v.clone());
+                }
             }
         }
     }
 }
 
+fn fix_deprecated_in_inline_table(
+    table: &mut InlineTable,
+    path: &[String],
+    fix: bool,
+    found: &mut Vec<String>,
+) {
+    let (from, to) = ("default_features", "default-features");
+    if !table.contains_key(from) {
+        return;
+    }
+    found.push(deprecated_key_message(path, from, to));
+    if !fix {
+        return;
+    }
+
+    if table.contains_key(to) {
+        table.remove(from);
+        return;
+    }
+    let table_clone = table.clone();
+    table.clear();
+    for (k, _) in table_clone.iter() {
+        let Some((k, Item::Value(v))) = table_clone.get_key_value(k) else {
+            continue;
+        };
+        if k.get() == from {
+            let renamed = Key::new(to).with_leaf_decor(k.leaf_decor().clone());
+            table.insert_formatted(&renamed, v.clone());
+        } else {
+            table.insert_formatted(k, v.clone());
+        }
+    }
+}
+
+/// The form of a target cfg expression that target tables are sorted by, without
+/// whitespace and with all quotes as `"`, so `cfg(unix)` and `cfg( unix )` sort
+/// together.
+fn normalize_cfg(cfg: &str) -> String {
+    cfg.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| if c == '\'' { '"' } else { c })
+        .collect()
+}
+
+fn item_at_path<'a, S: AsRef<str>>(
+    table: &'a mut Table,
+    path: &[S],
+) -> Option<&'a mut Item> {
+    let (first, rest) = path.split_first()?;
+    let item = table.get_mut(first.as_ref())?;
+    if rest.is_empty() {
+        Some(item)
+    } else {
+        item_at_path(item.as_table_mut()?, rest)
+    }
+}
+
 fn walk_tables_set_position(table: &mut Table, idx: &mut isize) {
     for (_, item) in table.iter_mut() {
         match item {
@@ -490,14 +1553,19 @@ fn walk_tables_set_position(table: &mut Table, idx: &mut isize) {
 mod test {
     use std::fs;
 
-    use super::MATCHER;
-    use crate::test_utils::assert_eq;
+    use super::{GroupBy, MATCHER};
+    use crate::{fmt::Config, test_utils::assert_eq};
+
+    /// A config without a table order, so tables are sorted lexicographically.
+    fn lexical() -> Config {
+        Config { table_order: vec![], ..Config::default() }
+    }
 
     #[test]
     fn toml_edit_check() {
         let input = fs::read_to_string("examp/workspace.toml").unwrap();
         let expected = fs::read_to_string("examp/workspace.sorted.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, false, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
         assert_eq(expected, sorted);
     }
 
@@ -505,9 +1573,8 @@ mod test {
     fn toml_combined_key_check() {
         let input = fs::read_to_string("examp/tun.toml").unwrap();
         let expected = fs::read_to_string("examp/tun.sorted.toml").unwrap();
-        let o = crate::fmt::DEF_TABLE_ORDER;
-        let o = o.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
-        let sorted = super::sort_toml(&input, MATCHER, false, &o);
+        let sorted =
+            super::sort_toml(&input, MATCHER, false, &Config::default()).unwrap();
 
         assert_eq(expected, sorted);
     }
@@ -516,7 +1583,21 @@ mod test {
     fn toml_workspace_deps_edit_check() {
         let input = fs::read_to_string("examp/workspace_deps.toml").unwrap();
         let expected = fs::read_to_string("examp/workspace_deps.sorted.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, false, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn blank_lines_stay_in_place() {
+        // The blank lines above a header stay where they are in the document when the
+        // tables move, the comments above a header move with it.
+        let input = fs::read_to_string("examp/blank_lines.toml").unwrap();
+        let expected = fs::read_to_string("examp/blank_lines.sorted.toml").unwrap();
+        let config = Config::default();
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, false, &config).unwrap();
         assert_eq(expected, sorted);
     }
 
@@ -524,14 +1605,258 @@ mod test {
     fn grouped_check() {
         let input = fs::read_to_string("examp/ruma.toml").unwrap();
         let expected = fs::read_to_string("examp/ruma.sorted.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn grouped_by_comments() {
+        let input = fs::read_to_string("examp/comment_groups.toml").unwrap();
+        let expected = fs::read_to_string("examp/comment_groups.sorted.toml").unwrap();
+        let config = Config { group_by: GroupBy::Comments, ..lexical() };
+        let sorted = super::sort_toml(&input, MATCHER, true, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, true, &config).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn grouped_by_comments_pin_top() {
+        let input = "[dependencies]\n# --- runtime ---\ntokio = \"1\"\nfutures = \"1\"\n\
+                     # cargo-sort: pin-top\nzstd = \"1\"\nanyhow = \"1\"\n";
+        let expected = "[dependencies]\n# --- runtime ---\n# cargo-sort: pin-top\n\
+                        zstd = \"1\"\nanyhow = \"1\"\nfutures = \"1\"\ntokio = \"1\"\n";
+        for group_by in [GroupBy::Comments, GroupBy::Both] {
+            let config = Config { group_by, ..lexical() };
+            let sorted = super::sort_toml(input, MATCHER, true, &config).unwrap();
+            assert_eq(expected, sorted);
+        }
+    }
+
+    #[test]
+    fn grouped_by_source() {
+        let input = fs::read_to_string("examp/sources.toml").unwrap();
+        let expected = fs::read_to_string("examp/sources.sorted.toml").unwrap();
+        let config =
+            Config { group_by_source: true, source_group_headers: true, ..lexical() };
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        // The groups are derived again, so moving a dependency to the registry
+        // moves it to that group
+        let moved = expected
+            .replace(r#"my-macros = { path = "../macros" }"#, r#"my-macros = "1""#);
+        let sorted = super::sort_toml(&moved, MATCHER, false, &config).unwrap();
+        assert!(sorted
+            .to_string()
+            .contains("# Registry dependencies\nmy-macros = \"1\"\nserde = "));
+
+        let sorted = super::sort_toml(&expected, MATCHER, false, &config).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn directives() {
+        let input = fs::read_to_string("examp/directives.toml").unwrap();
+        let expected = fs::read_to_string("examp/directives.sorted.toml").unwrap();
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, true, &lexical()).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn table_order_patterns() {
+        let input = fs::read_to_string("examp/order_patterns.toml").unwrap();
+        let expected = fs::read_to_string("examp/order_patterns.sorted.toml").unwrap();
+        let config = Config {
+            table_order: [
+                "package",
+                "lib",
+                "dependencies",
+                "dev-dependencies",
+                "target.*.dev-dependencies",
+                "...",
+                "package.metadata.*",
+                "badges",
+                "profile.*",
+            ]
+            .iter()
+            .map(|&s| s.to_owned())
+            .collect(),
+            ..Config::default()
+        };
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, false, &config).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn unlisted_implicit_tables_keep_their_order() {
+        let input = "[package]\nname = \"a\"\n\n[badges]\nfoo = 1\n\n[profile.release]\n\
+                     lto = true\n";
+        let sorted = super::sort_toml(input, MATCHER, false, &Config::default()).unwrap();
+        assert_eq(input, sorted);
+
+        let input = "[package]\nname = \"a\"\n\n[zzz]\na = 1\n\n[aaa.bbb]\nb = 1\n\n\
+                     [yyy]\nc = 1\n";
+        let config =
+            Config { table_order: vec!["package".to_owned()], ..Config::default() };
+        let sorted = super::sort_toml(input, MATCHER, false, &config).unwrap();
+        assert_eq(input, sorted);
+    }
+
+    #[test]
+    fn target_tables_after_base() {
+        let input = fs::read_to_string("examp/target_placement.toml").unwrap();
+        let expected = fs::read_to_string("examp/target_placement.sorted.toml").unwrap();
+        let config = Config { target_tables_after_base: true, ..lexical() };
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        // Listing `target` on its own does not pull the tables away from their base
+        let config = Config {
+            table_order: ["package", "target", "dependencies", "dev-dependencies"]
+                .iter()
+                .map(|&s| s.to_owned())
+                .collect(),
+            ..config
+        };
+        let sorted = super::sort_toml(&expected, MATCHER, false, &config).unwrap();
         assert_eq(expected, sorted);
     }
 
+    #[test]
+    fn profiles() {
+        let input = fs::read_to_string("examp/profiles.toml").unwrap();
+        let expected = fs::read_to_string("examp/profiles.sorted.toml").unwrap();
+        let sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted =
+            super::sort_toml(&input, MATCHER, false, &Config::default()).unwrap();
+        assert_eq(&expected, sorted);
+    }
+
+    #[test]
+    fn lints() {
+        let input = fs::read_to_string("examp/lints.toml").unwrap();
+        let expected = fs::read_to_string("examp/lints.sorted.toml").unwrap();
+        let config = Config { sort_lints: true, ..lexical() };
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        // Lints are left alone unless `sort_lints` is set
+        let sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
+        assert_eq(input, sorted);
+    }
+
+    #[test]
+    fn array_comments() {
+        let input = fs::read_to_string("examp/arrays.toml").unwrap();
+        let expected = fs::read_to_string("examp/arrays.sorted.toml").unwrap();
+        let sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, false, &lexical()).unwrap();
+        assert_eq(&expected, sorted);
+    }
+
+    #[test]
+    fn sort_arrays_at_paths() {
+        let input = fs::read_to_string("examp/sort_arrays.toml").unwrap();
+        let expected = fs::read_to_string("examp/sort_arrays.sorted.toml").unwrap();
+        let config = Config {
+            sort_arrays: ["package.keywords", "package.categories", "package.include"]
+                .into_iter()
+                .chain(["package.metadata.docs.rs.*"])
+                .map(str::to_owned)
+                .collect(),
+            ..lexical()
+        };
+        let sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let unsorted = super::unsorted_arrays(&input.parse().unwrap(), &config);
+        assert_eq!(
+            unsorted,
+            [
+                "package.keywords",
+                "package.categories",
+                "package.include",
+                "package.metadata.docs.rs.features",
+                "package.metadata.docs.rs.targets",
+            ]
+        );
+        assert!(super::unsorted_arrays(&expected.parse().unwrap(), &config).is_empty());
+    }
+
+    #[test]
+    fn dotted_path_patterns() {
+        let path = |p: &[&str]| p.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+        let matches = super::dotted_path_matches;
+        assert!(matches("package.keywords", &path(&["package", "keywords"])));
+        assert!(!matches("package", &path(&["package", "keywords"])));
+        assert!(matches("package.*", &path(&["package", "keywords"])));
+        assert!(matches("a.docs.rs.*", &path(&["a", "docs.rs", "features"])));
+        assert!(matches("a.\"docs.rs\".*", &path(&["a", "docs.rs", "features"])));
+        assert!(!matches("a.\"docs.rs\".*", &path(&["a", "docs", "rs", "features"])));
+        assert!(!matches("package.key", &path(&["package", "keywords"])));
+    }
+
+    #[test]
+    fn array_dedupe() {
+        let input = "[workspace]\nmembers = [\n    \"b\", # b\n    \"a\",\n    # again\n    \"b\",\n]\n";
+        let doc = input.parse().unwrap();
+        assert_eq!(
+            super::duplicate_array_entries(&doc, &MATCHER, &lexical()),
+            ["`workspace.members` contains \"b\" more than once"]
+        );
+
+        let sorted = super::sort_toml(input, MATCHER, false, &lexical()).unwrap();
+        assert_eq(
+            "[workspace]\nmembers = [\n    \"a\",\n    \"b\", # b\n    # again\n    \"b\",\n]\n",
+            sorted,
+        );
+
+        let config = Config { dedupe_arrays: true, ..lexical() };
+        let sorted = super::sort_toml(input, MATCHER, false, &config).unwrap();
+        assert_eq("[workspace]\nmembers = [\n    \"a\",\n    \"b\", # b\n]\n", sorted);
+    }
+
+    #[test]
+    fn deprecated_keys() {
+        let input = fs::read_to_string("examp/deprecated.toml").unwrap();
+        let expected = fs::read_to_string("examp/deprecated.sorted.toml").unwrap();
+        let config = Config { fix_deprecated_keys: true, ..lexical() };
+        let mut sorted = super::sort_toml(&input, MATCHER, false, &config).unwrap();
+        assert!(super::deprecated_keys(&mut sorted, false).is_empty());
+        assert_eq(&expected, sorted);
+
+        // Without the fix the underscore tables are sorted like the hyphenated ones
+        let mut sorted = super::sort_toml(&input, MATCHER, false, &lexical()).unwrap();
+        let found = super::deprecated_keys(&mut sorted, false);
+        assert_eq!(found.len(), 5);
+        assert!(found.contains(
+            &"`dependencies.serde.default_features` is deprecated, use \
+              `default-features` instead"
+                .to_owned()
+        ));
+        let out = sorted.to_string();
+        assert!(out.contains("[build_dependencies]\nbindgen = \"0.69\"\ncc = \"1\"\n"));
+    }
+
     #[test]
     fn sort_correct() {
         let input = fs::read_to_string("examp/right.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_eq(input, sorted);
     }
 
@@ -539,14 +1864,14 @@ mod test {
     fn sort_comments() {
         let input = fs::read_to_string("examp/comments.toml").unwrap();
         let expected = fs::read_to_string("examp/comments.sorted.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_eq(expected, sorted);
     }
 
     #[test]
     fn sort_tables() {
         let input = fs::read_to_string("examp/fend.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_ne!(input, sorted.to_string());
         // println!("{}", sorted.to_string());
     }
@@ -554,21 +1879,63 @@ mod test {
     #[test]
     fn sort_devfirst() {
         let input = fs::read_to_string("examp/reorder.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_eq(input, sorted);
 
         let input = fs::read_to_string("examp/noreorder.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_eq(input, sorted);
     }
 
     #[test]
     fn issue_104() {
         let input = fs::read_to_string("regressions/104.toml").unwrap();
-        let sorted = super::sort_toml(&input, MATCHER, true, &[]);
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
         assert_eq(input, sorted);
     }
 
+    #[test]
+    fn grouped_target_tables() {
+        let input = fs::read_to_string("regressions/target_grouped.toml").unwrap();
+        let expected =
+            fs::read_to_string("regressions/target_grouped.sorted.toml").unwrap();
+        let sorted = super::sort_toml(&input, MATCHER, true, &lexical()).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, true, &lexical()).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn grouped_decor() {
+        let input = fs::read_to_string("regressions/grouped_decor.toml").unwrap();
+        let expected =
+            fs::read_to_string("regressions/grouped_decor.sorted.toml").unwrap();
+        let config = Config { group_by: GroupBy::Both, ..lexical() };
+        let sorted = super::sort_toml(&input, MATCHER, true, &config).unwrap();
+        assert_eq(&expected, sorted);
+
+        let sorted = super::sort_toml(&expected, MATCHER, true, &config).unwrap();
+        assert_eq(expected, sorted);
+    }
+
+    #[test]
+    fn unusual_toml() {
+        let input =
+            "[dependencies.foo]\nversion = \"1\"\n\n[[dependencies.foo.bar]]\na = 1\n\n\
+             [dependencies]\nb = \"1\"\na = \"1\"\n";
+        let sorted = super::sort_toml(input, MATCHER, false, &lexical()).unwrap();
+        // The array of tables is placed after the table it is nested in, the blank
+        // lines stay where they were
+        assert_eq(
+            "[dependencies]\na = \"1\"\nb = \"1\"\n\n[dependencies.foo]\nversion = \"1\"\n\n\
+             [[dependencies.foo.bar]]\na = 1\n",
+            sorted,
+        );
+
+        assert!(super::sort_toml("[dependencies", MATCHER, false, &lexical()).is_err());
+    }
+
     #[test]
     fn reorder() {
         let input = fs::read_to_string("examp/clippy.toml").unwrap();
@@ -576,14 +1943,18 @@ mod test {
             &input,
             MATCHER,
             true,
-            &[
-                "package".to_owned(),
-                "features".to_owned(),
-                "dependencies".to_owned(),
-                "build-dependencies".to_owned(),
-                "dev-dependencies".to_owned(),
-            ],
-        );
+            &Config {
+                table_order: vec![
+                    "package".to_owned(),
+                    "features".to_owned(),
+                    "dependencies".to_owned(),
+                    "build-dependencies".to_owned(),
+                    "dev-dependencies".to_owned(),
+                ],
+                ..Config::default()
+            },
+        )
+        .unwrap();
         assert_ne!(input, sorted.to_string());
     }
 }